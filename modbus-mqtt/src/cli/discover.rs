@@ -0,0 +1,264 @@
+use super::{from_str, ConnectionArgs};
+use crate::modbus::{connection::ModbusProto, register::RegisterType};
+use clap::Args;
+use serde::Serialize;
+use std::io::ErrorKind;
+use tokio_modbus::{
+    client::Context as ModbusClient,
+    prelude::{Client, Reader, Request, Response, Slave, SlaveContext},
+};
+use tracing::debug;
+
+/// Read Device Identification (FC43/14)
+const READ_DEVICE_IDENTIFICATION: u8 = 0x2b;
+const MEI_TYPE: u8 = 0x0e;
+/// The basic category of identification objects: vendor name, product code and revision
+const BASIC_IDENTIFICATION: u8 = 0x01;
+
+/// Units which are present respond quickly, so there's no need to wait as long for each as with other commands
+const DEFAULT_TIMEOUT: &str = "500ms";
+
+#[derive(Args, Debug)]
+#[clap(mut_arg("timeout", |arg| arg.default_value(DEFAULT_TIMEOUT)))]
+pub struct DiscoverArgs {
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    #[clap(long, default_value_t = 1, help = "First unit ID to probe")]
+    first: u8,
+
+    #[clap(long, default_value_t = 247, help = "Last unit ID to probe")]
+    last: u8,
+
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Address of the register to read from each unit"
+    )]
+    address: u16,
+
+    #[clap(
+        long,
+        default_value = "holding",
+        value_parser = from_str::<RegisterType>,
+        help = "input or holding"
+    )]
+    register_type: RegisterType,
+
+    #[clap(long, help = "Print a JSON object per unit instead of a table")]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Unit {
+    unit: u8,
+
+    /// The exception the unit responded to the read with, if any. Responding with an exception still shows that the
+    /// unit is present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exception: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<Identity>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct Identity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vendor_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+}
+
+pub(super) async fn run(args: DiscoverArgs) -> crate::Result<()> {
+    if args.first == 0 || args.first > args.last {
+        return Err("unit IDs to probe must be from 1, with --first not after --last".into());
+    }
+
+    let config = args.connection.config()?;
+    let address = args
+        .address
+        .checked_add_signed(config.address_offset.into())
+        .ok_or("--address-offset would take the address out of range")?;
+
+    // tokio_modbus can only frame the variable-length responses of device identification over TCP
+    #[cfg(feature = "tcp")]
+    let identify = matches!(config.settings, ModbusProto::Tcp { .. });
+    #[cfg(not(feature = "tcp"))]
+    let identify = false;
+
+    let connect = || {
+        args.connection
+            .timeout(config.settings.connect(Slave(args.first)))
+    };
+    let mut client = connect().await?;
+
+    if !args.json {
+        println!("{:>4}  {:<48}  IDENTITY", "UNIT", "RESPONSE");
+    }
+
+    let mut found = 0;
+    for unit in args.first..=args.last {
+        client.set_slave(Slave(unit));
+
+        let probe = tokio::time::timeout(
+            args.connection.timeout,
+            read(&mut client, args.register_type, address),
+        )
+        .await;
+        debug!(unit, ?probe);
+
+        let exception = match probe {
+            Ok(Ok(_)) => None,
+            // tokio_modbus reports exception responses as `Other`
+            Ok(Err(error)) if error.kind() == ErrorKind::Other => Some(error.to_string()),
+            Ok(Err(_)) | Err(_) => {
+                // A late or garbled response would otherwise be taken as the next unit's
+                client = connect().await?;
+                continue;
+            }
+        };
+
+        let identity = match identify {
+            true => {
+                let request = Request::Custom(
+                    READ_DEVICE_IDENTIFICATION,
+                    vec![MEI_TYPE, BASIC_IDENTIFICATION, 0],
+                );
+                match tokio::time::timeout(args.connection.timeout, client.call(request)).await {
+                    Ok(Ok(Response::Custom(_, data))) => parse_identification(&data),
+                    Ok(Ok(_)) => None,
+                    Ok(Err(error)) if error.kind() == ErrorKind::Other => None,
+                    Ok(Err(_)) | Err(_) => {
+                        client = connect().await?;
+                        None
+                    }
+                }
+            }
+            false => None,
+        };
+
+        found += 1;
+        let unit = Unit {
+            unit,
+            exception,
+            identity,
+        };
+
+        if args.json {
+            println!("{}", serde_json::to_string(&unit)?);
+        } else {
+            let response = match unit.exception {
+                Some(ref exception) => format!("exception: {exception}"),
+                None => "ok".into(),
+            };
+            let identity = unit.identity.as_ref().map_or_else(String::new, |identity| {
+                [
+                    &identity.vendor_name,
+                    &identity.product_code,
+                    &identity.revision,
+                ]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" ")
+            });
+            println!("{:>4}  {:<48}  {}", unit.unit, response, identity);
+        }
+    }
+
+    if !args.json {
+        println!(
+            "\n{found} of {} units responded",
+            args.last - args.first + 1
+        );
+    }
+
+    Ok(())
+}
+
+async fn read(
+    client: &mut ModbusClient,
+    register_type: RegisterType,
+    address: u16,
+) -> std::io::Result<Vec<u16>> {
+    match register_type {
+        RegisterType::Input => client.read_input_registers(address, 1).await,
+        RegisterType::Holding => client.read_holding_registers(address, 1).await,
+    }
+}
+
+/// Parse the objects of a Read Device Identification response, following the function code
+fn parse_identification(data: &[u8]) -> Option<Identity> {
+    let &[MEI_TYPE, _code, _conformity, _more, _next, count, ref objects @ ..] = data else {
+        return None;
+    };
+    let mut objects = objects;
+
+    let mut identity = Identity::default();
+    for _ in 0..count {
+        let &[id, length, ref rest @ ..] = objects else {
+            return None;
+        };
+        let value = rest.get(..length as usize)?;
+        let value = Some(String::from_utf8_lossy(value).trim().to_owned());
+        match id {
+            0 => identity.vendor_name = value,
+            1 => identity.product_code = value,
+            2 => identity.revision = value,
+            _ => {}
+        }
+        objects = &rest[length as usize..];
+    }
+
+    Some(identity)
+}
+
+#[test]
+fn test_default_timeout() {
+    use clap::Parser;
+    use std::time::Duration;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[clap(flatten)]
+        discover: DiscoverArgs,
+    }
+
+    let args = |extra: &[&str]| {
+        let base = ["test", "--proto", "tcp", "--host", "10.10.10.219"];
+        TestCli::parse_from(base.iter().chain(extra)).discover
+    };
+    assert_eq!(args(&[]).connection.timeout, Duration::from_millis(500));
+    assert_eq!(
+        args(&["--timeout", "2s"]).connection.timeout,
+        Duration::from_secs(2)
+    );
+}
+
+#[test]
+fn test_parse_identification() {
+    let mut data = vec![MEI_TYPE, 1, 1, 0, 0, 3];
+    data.extend([0, 4]);
+    data.extend(b"Acme");
+    data.extend([1, 5]);
+    data.extend(b"SIM-1");
+    data.extend([2, 3]);
+    data.extend(b"1.0");
+
+    assert_eq!(
+        parse_identification(&data),
+        Some(Identity {
+            vendor_name: Some("Acme".into()),
+            product_code: Some("SIM-1".into()),
+            revision: Some("1.0".into()),
+        })
+    );
+
+    // Truncated
+    assert_eq!(parse_identification(&data[..data.len() - 1]), None);
+    assert_eq!(parse_identification(&[MEI_TYPE, 1]), None);
+}
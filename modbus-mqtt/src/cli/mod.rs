@@ -0,0 +1,284 @@
+//! One-off commands for troubleshooting and commissioning devices, which talk to them directly rather than via MQTT.
+
+use crate::modbus::{
+    connection::{self, Config},
+    register::{Register, RegisterType},
+    Handle,
+};
+use clap::{Args, Subcommand};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value as JSON};
+use std::future::Future;
+use std::time::Duration;
+
+mod discover;
+mod read;
+mod scan;
+mod validate;
+mod write;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Read registers and print their raw and decoded values
+    Read(read::ReadArgs),
+
+    /// Write a value to a holding register, then read it back
+    Write(write::WriteArgs),
+
+    /// Find the readable registers in a range of addresses
+    Scan(scan::ScanArgs),
+
+    /// Find which unit IDs respond behind a serial bus or TCP gateway
+    DiscoverUnits(discover::DiscoverArgs),
+
+    /// Check a connection config file, reporting every problem with it
+    Validate(validate::ValidateArgs),
+
+    /// Print a JSON Schema for connection (or register) configs, for editors to validate and autocomplete them with
+    Schema(validate::SchemaArgs),
+}
+
+impl Command {
+    pub async fn run(self) -> crate::Result<()> {
+        match self {
+            Command::Read(args) => read::run(args).await,
+            Command::Write(args) => write::run(args).await,
+            Command::Scan(args) => scan::run(args).await,
+            Command::DiscoverUnits(args) => discover::run(args).await,
+            Command::Validate(args) => validate::run(args).await,
+            Command::Schema(args) => validate::schema(args).await,
+        }
+    }
+}
+
+/// The same connection parameters as a connection config published to `$prefix/$id/connect`
+#[derive(Args, Debug)]
+pub struct ConnectionArgs {
+    #[clap(long, help = "Protocol to connect with: tcp, rtu or winet-s")]
+    proto: String,
+
+    #[clap(long, help = "Host to connect to, for tcp and winet-s")]
+    host: Option<String>,
+
+    #[clap(long, help = "Port to connect to, for tcp [default: 502]")]
+    port: Option<u16>,
+
+    #[clap(long, value_hint = clap::ValueHint::FilePath, help = "Serial device, for rtu")]
+    tty: Option<String>,
+
+    #[clap(long, help = "Baud rate, for rtu")]
+    baud_rate: Option<u32>,
+
+    #[clap(long, help = "Five, Six, Seven or Eight, for rtu [default: Eight]")]
+    data_bits: Option<String>,
+
+    #[clap(long, help = "One or Two, for rtu [default: One]")]
+    stop_bits: Option<String>,
+
+    #[clap(long, help = "None, Software or Hardware, for rtu [default: None]")]
+    flow_control: Option<String>,
+
+    #[clap(long, help = "None, Odd or Even, for rtu [default: None]")]
+    parity: Option<String>,
+
+    #[clap(long, visible_alias = "slave", help = "Unit ID of the device")]
+    unit: Option<u8>,
+
+    #[clap(
+        long,
+        allow_negative_numbers = true,
+        help = "Offset added to every register address"
+    )]
+    address_offset: Option<i8>,
+
+    #[clap(
+        long,
+        default_value = "5s",
+        value_parser = humantime_serde::re::humantime::parse_duration,
+        help = "How long to wait for the device to respond to each request"
+    )]
+    timeout: Duration,
+}
+
+impl ConnectionArgs {
+    /// The connection config these arguments describe, parsed just as a published one would be
+    fn config(&self) -> crate::Result<Config> {
+        let mut config = Map::new();
+        let mut set = |key: &str, value: JSON| {
+            if !value.is_null() {
+                config.insert(key.to_owned(), value);
+            }
+        };
+
+        set("proto", json!(self.proto));
+        set("host", json!(self.host));
+        set("port", json!(self.port));
+        set("tty", json!(self.tty));
+        set("baud_rate", json!(self.baud_rate));
+        set("data_bits", json!(self.data_bits));
+        set("stop_bits", json!(self.stop_bits));
+        set("flow_control", json!(self.flow_control));
+        set("parity", json!(self.parity));
+        set("unit", json!(self.unit));
+        set("address_offset", json!(self.address_offset));
+
+        Ok(serde_json::from_value(JSON::Object(config))?)
+    }
+
+    async fn connect(&self) -> crate::Result<Handle> {
+        let config = self.config()?;
+        self.timeout(connection::connect(&config)).await
+    }
+
+    /// Wait for a request to the device, giving up after `--timeout`
+    async fn timeout<T>(
+        &self,
+        request: impl Future<Output = crate::Result<T>>,
+    ) -> crate::Result<T> {
+        match tokio::time::timeout(self.timeout, request).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "the device did not respond within {}",
+                humantime_serde::re::humantime::format_duration(self.timeout)
+            )
+            .into()),
+        }
+    }
+}
+
+/// How to decode (or encode) values, using the same options as a register config
+#[derive(Args, Debug)]
+pub struct ParseArgs {
+    #[clap(
+        long = "type",
+        default_value = "u16",
+        help = "Type of the value, e.g. u16, s32, f32, string"
+    )]
+    value_type: String,
+
+    #[clap(
+        long,
+        help = "Order of the value's bytes on the wire, e.g. CDAB [default: ABCD]"
+    )]
+    byte_order: Option<String>,
+
+    #[clap(
+        long,
+        allow_negative_numbers = true,
+        help = "Power of 10 to scale numeric values by"
+    )]
+    scale: Option<i8>,
+
+    #[clap(long, help = "Length of string values, in registers")]
+    length: Option<u8>,
+}
+
+impl ParseArgs {
+    /// A register at `address` with these parse options, and any other `options` of a register config
+    fn register(
+        &self,
+        address: u16,
+        register_type: RegisterType,
+        options: JSON,
+    ) -> crate::Result<Register> {
+        let mut register = json!({
+            "address": address,
+            "register_type": register_type,
+            "type": self.value_type,
+        });
+        let fields = register.as_object_mut().unwrap();
+        if let Some(ref byte_order) = self.byte_order {
+            fields.insert("byte_order".into(), json!(byte_order));
+        }
+        if let Some(scale) = self.scale {
+            fields.insert("scale".into(), json!(scale));
+        }
+        if let Some(length) = self.length {
+            fields.insert("length".into(), json!(length));
+        }
+        if let JSON::Object(options) = options {
+            fields.extend(options);
+        }
+
+        Ok(serde_json::from_value(register)?)
+    }
+}
+
+/// Parse an argument as a string in the same way as config values, e.g. for `"holding"` or `"fc16"`
+fn from_str<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_value(JSON::String(value.to_owned())).map_err(|error| error.to_string())
+}
+
+/// The raw words of a value as hex, as in the `envelope` payload format
+fn hex(words: &[u16]) -> Vec<String> {
+    words.iter().map(|word| format!("{word:04x}")).collect()
+}
+
+#[cfg(test)]
+#[derive(clap::Parser)]
+struct TestCli {
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    #[clap(flatten)]
+    parse: ParseArgs,
+}
+
+#[test]
+fn test_connection_args() {
+    use clap::Parser;
+
+    let cli = TestCli::parse_from([
+        "test",
+        "--proto",
+        "tcp",
+        "--host",
+        "10.10.10.219",
+        "--slave",
+        "1",
+        "--address-offset",
+        "-1",
+    ]);
+    let config = cli.connection.config().unwrap();
+    assert_eq!(config.unit, tokio_modbus::slave::Slave(1));
+    assert_eq!(config.address_offset, -1);
+    assert!(matches!(
+        config.settings,
+        connection::ModbusProto::Tcp { ref host, port: 502 } if host == "10.10.10.219"
+    ));
+    assert_eq!(cli.connection.timeout, Duration::from_secs(5));
+
+    let cli = TestCli::parse_from(["test", "--proto", "rtu", "--tty", "/dev/ttyUSB0"]);
+    assert!(cli.connection.config().is_err(), "baud rate is required");
+}
+
+#[test]
+fn test_parse_args() {
+    use clap::Parser;
+
+    let cli = TestCli::parse_from([
+        "test",
+        "--proto",
+        "tcp",
+        "--type",
+        "s32",
+        "--byte-order",
+        "CDAB",
+        "--scale",
+        "-1",
+    ]);
+    let register = cli
+        .parse
+        .register(5000, RegisterType::Holding, json!({ "writable": true }))
+        .unwrap();
+    assert_eq!(register.register_type, RegisterType::Holding);
+    assert!(register.writable);
+    assert_eq!(register.size(), 2);
+    assert_eq!(register.parse_words(&[0xfff6, 0xffff]), json!(-1));
+
+    let cli = TestCli::parse_from([
+        "test", "--proto", "tcp", "--type", "string", "--length", "4",
+    ]);
+    let register = cli.parse.register(4989, RegisterType::Input, JSON::Null);
+    assert_eq!(register.unwrap().size(), 4);
+}
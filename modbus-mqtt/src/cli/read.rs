@@ -0,0 +1,130 @@
+use super::{from_str, hex, ConnectionArgs, ParseArgs};
+use crate::modbus::register::RegisterType;
+use clap::Args;
+use serde::Serialize;
+use serde_json::Value as JSON;
+
+/// Modbus limits reads to 125 registers
+const MAX_READ: usize = 125;
+
+#[derive(Args, Debug)]
+pub struct ReadArgs {
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    #[clap(long, help = "Address of the first register to read")]
+    address: u16,
+
+    #[clap(
+        long,
+        default_value = "input",
+        value_parser = from_str::<RegisterType>,
+        help = "input or holding"
+    )]
+    register_type: RegisterType,
+
+    #[clap(flatten)]
+    parse: ParseArgs,
+
+    #[clap(
+        long,
+        default_value_t = 1,
+        help = "Number of consecutive values to read"
+    )]
+    count: u8,
+
+    #[clap(long, help = "Print values as JSON lines instead of a table")]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Reading {
+    address: u16,
+    raw: Vec<String>,
+    value: JSON,
+}
+
+pub(super) async fn run(args: ReadArgs) -> crate::Result<()> {
+    let register = args
+        .parse
+        .register(args.address, args.register_type, JSON::Null)?;
+    let size = register.size() as usize;
+    let quantity = quantity(args.address, size, args.count)?;
+
+    let modbus = args.connection.connect().await?;
+    let words = args
+        .connection
+        .timeout(async {
+            match args.register_type {
+                RegisterType::Input => {
+                    modbus
+                        .read_input_register(args.address, quantity as u8)
+                        .await
+                }
+                RegisterType::Holding => {
+                    modbus
+                        .read_holding_register(args.address, quantity as u8)
+                        .await
+                }
+            }
+        })
+        .await?;
+
+    // The addresses were checked to fit above
+    let readings = words.chunks(size).zip(0..).map(|(words, index)| Reading {
+        address: args.address + (index * size) as u16,
+        raw: hex(words),
+        value: register.parse_words(words),
+    });
+
+    for reading in readings {
+        if args.json {
+            println!("{}", serde_json::to_string(&reading)?);
+        } else {
+            println!(
+                "{:>5}  {:<24}  {}",
+                reading.address,
+                reading.raw.join(" "),
+                reading.value
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The number of registers to read for `count` values of `size` registers each, checking that they can be read at once.
+fn quantity(address: u16, size: usize, count: u8) -> crate::Result<usize> {
+    if count == 0 {
+        return Err("--count must be at least 1".into());
+    }
+    if size == 0 {
+        return Err("values of this type take up no registers, so there is nothing to read".into());
+    }
+
+    let quantity = size * count as usize;
+    if quantity > MAX_READ {
+        return Err(format!(
+            "{quantity} registers were requested, but at most {MAX_READ} can be read at once"
+        )
+        .into());
+    }
+    if address.checked_add(quantity as u16 - 1).is_none() {
+        return Err(
+            format!("{quantity} registers from {address} run past the last address").into(),
+        );
+    }
+
+    Ok(quantity)
+}
+
+#[test]
+fn test_quantity() {
+    assert_eq!(quantity(100, 2, 3).unwrap(), 6);
+    assert_eq!(quantity(65534, 1, 2).unwrap(), 2);
+
+    assert!(quantity(100, 2, 0).is_err());
+    assert!(quantity(100, 0, 1).is_err());
+    assert!(quantity(100, 2, 63).is_err());
+    assert!(quantity(65534, 1, 3).is_err());
+}
@@ -0,0 +1,280 @@
+use super::{from_str, hex, ConnectionArgs};
+use crate::modbus::{
+    register::{RegisterParse, RegisterType},
+    Handle,
+};
+use crate::Error;
+use clap::Args;
+use serde::Serialize;
+use serde_json::{json, Map, Value as JSON};
+use std::future::Future;
+use tracing::debug;
+
+/// Modbus limits reads to 125 registers
+const MAX_READ: u16 = 125;
+
+/// Interpretations of each readable register, and of it together with the next one where that is readable too
+const CANDIDATES: &[(&str, usize)] = &[("u16", 1), ("i16", 1), ("u32", 2), ("f32", 2)];
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    #[clap(long, help = "First address to scan")]
+    start: u16,
+
+    #[clap(long, help = "Last address to scan")]
+    end: u16,
+
+    #[clap(
+        long,
+        value_parser = from_str::<RegisterType>,
+        help = "input or holding [default: both]"
+    )]
+    register_type: Option<RegisterType>,
+
+    #[clap(
+        long,
+        default_value_t = MAX_READ,
+        value_parser = clap::value_parser!(u16).range(1..=MAX_READ as i64),
+        help = "Number of registers to read at once"
+    )]
+    chunk_size: u16,
+
+    #[clap(long, help = "Print a JSON object per register instead of a table")]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+    register_type: RegisterType,
+    address: u16,
+    raw: String,
+
+    #[serde(flatten)]
+    candidates: Map<String, JSON>,
+}
+
+pub(super) async fn run(args: ScanArgs) -> crate::Result<()> {
+    if args.start > args.end {
+        return Err("--start must not be after --end".into());
+    }
+
+    let modbus = args.connection.connect().await?;
+    let register_types = match args.register_type {
+        Some(register_type) => vec![register_type],
+        None => vec![RegisterType::Input, RegisterType::Holding],
+    };
+
+    if !args.json {
+        println!(
+            "{:<8} {:>7}  {:<4}  {:>6}  {:>6}  {:>10}  {:>14}  STRING",
+            "TYPE", "ADDRESS", "RAW", "U16", "I16", "U32", "F32"
+        );
+    }
+
+    for register_type in register_types {
+        let found = scan(args.start, args.end, args.chunk_size, |address, count| {
+            args.connection
+                .timeout(read(&modbus, register_type, address, count))
+        })
+        .await?;
+
+        for (index, &(address, word)) in found.iter().enumerate() {
+            let words = match found.get(index + 1) {
+                Some(&(next, next_word)) if address.checked_add(1) == Some(next) => {
+                    vec![word, next_word]
+                }
+                _ => vec![word],
+            };
+            let row = Row {
+                register_type,
+                address,
+                raw: hex(&[word]).concat(),
+                candidates: candidates(&words),
+            };
+
+            if args.json {
+                println!("{}", serde_json::to_string(&row)?);
+            } else {
+                let column = |name: &str| match row.candidates.get(name) {
+                    Some(JSON::String(string)) => format!("{string:?}"),
+                    Some(value) => value.to_string(),
+                    None => String::new(),
+                };
+                println!(
+                    "{:<8} {:>7}  {:<4}  {:>6}  {:>6}  {:>10}  {:>14}  {}",
+                    serde_json::to_value(register_type)?
+                        .as_str()
+                        .unwrap_or_default(),
+                    row.address,
+                    row.raw,
+                    column("u16"),
+                    column("i16"),
+                    column("u32"),
+                    column("f32"),
+                    column("string"),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn read(
+    modbus: &Handle,
+    register_type: RegisterType,
+    address: u16,
+    count: u8,
+) -> crate::Result<Vec<u16>> {
+    match register_type {
+        RegisterType::Input => modbus.read_input_register(address, count).await,
+        RegisterType::Holding => modbus.read_holding_register(address, count).await,
+    }
+}
+
+/// Read every address from `start` to `end` (inclusive) in chunks of up to `chunk_size`, returning those which could be
+/// read along with their values.
+///
+/// Chunks which the device refuses to read (or doesn't respond to) are split in half and retried, so that readable
+/// addresses either side of unreadable ones are still found.
+async fn scan<F, Fut>(
+    start: u16,
+    end: u16,
+    chunk_size: u16,
+    mut read: F,
+) -> crate::Result<Vec<(u16, u16)>>
+where
+    F: FnMut(u16, u8) -> Fut,
+    Fut: Future<Output = crate::Result<Vec<u16>>>,
+{
+    let mut found = vec![];
+
+    // Chunks which were split, to be read (last first) before moving on
+    let mut pending: Vec<(u16, u16)> = vec![];
+    let mut next = start as u32;
+
+    loop {
+        let (address, count) = match pending.pop() {
+            Some(chunk) => chunk,
+            None if next <= end as u32 => {
+                let count = (end as u32 - next + 1).min(chunk_size as u32) as u16;
+                let chunk = (next as u16, count);
+                next += count as u32;
+                chunk
+            }
+            None => break,
+        };
+
+        match read(address, count as u8).await {
+            Ok(words) => found.extend(
+                (0..count)
+                    .zip(words)
+                    .map(|(offset, word)| (address + offset, word)),
+            ),
+            // The connection itself has failed, so there's no point in carrying on
+            Err(error @ (Error::SendError | Error::RecvError)) => return Err(error),
+            Err(error) if count == 1 => debug!(address, ?error, "unreadable"),
+            Err(_) => {
+                let half = count / 2;
+                pending.push((address + half, count - half));
+                pending.push((address, half));
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Possible interpretations of a register's words
+fn candidates(words: &[u16]) -> Map<String, JSON> {
+    let parse = |spec: JSON, words: &[u16]| {
+        serde_json::from_value::<RegisterParse>(spec)
+            .unwrap()
+            .parse_words(words)
+    };
+
+    let mut candidates = Map::new();
+    for &(name, size) in CANDIDATES {
+        if let Some(words) = words.get(..size) {
+            let value = parse(json!({ "type": name }), words);
+            if !value.is_null() {
+                candidates.insert(name.to_owned(), value);
+            }
+        }
+    }
+
+    // Only printable text is likely to be a string
+    if let JSON::String(string) = parse(json!({ "type": "string", "length": 1 }), &words[..1]) {
+        if !string.is_empty() && string.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            candidates.insert("string".to_owned(), JSON::String(string));
+        }
+    }
+
+    candidates
+}
+
+#[tokio::test]
+async fn test_scan() {
+    // A device which refuses reads which include 103 or 104, or go past 110
+    let mut reads = vec![];
+    let mut device = |address: u16, count: u8| {
+        reads.push((address, count));
+        let addresses = address..address + count as u16;
+        let result = if addresses.clone().any(|a| a == 103 || a == 104 || a > 110) {
+            Err("Illegal data address".into())
+        } else {
+            Ok(addresses.map(|a| a * 2).collect())
+        };
+        std::future::ready(result)
+    };
+
+    let found = scan(100, 112, 8, &mut device).await.unwrap();
+    assert_eq!(
+        found,
+        [100, 101, 102, 105, 106, 107, 108, 109, 110]
+            .map(|a| (a, a * 2))
+            .to_vec()
+    );
+    assert_eq!(
+        reads,
+        [
+            (100, 8),
+            (100, 4),
+            (100, 2),
+            (102, 2),
+            (102, 1),
+            (103, 1),
+            (104, 4),
+            (104, 2),
+            (104, 1),
+            (105, 1),
+            (106, 2),
+            (108, 5),
+            (108, 2),
+            (110, 3),
+            (110, 1),
+            (111, 2),
+            (111, 1),
+            (112, 1),
+        ]
+    );
+
+    // A failed connection stops the scan
+    let failed = scan(0, 10, 8, |_, _| std::future::ready(Err(Error::SendError))).await;
+    assert!(matches!(failed, Err(Error::SendError)));
+}
+
+#[test]
+fn test_candidates() {
+    assert_eq!(
+        JSON::Object(candidates(&[0x4148, 0x0000])),
+        json!({ "u16": 16712, "i16": 16712, "u32": 1095237632, "f32": 12.5, "string": "AH" })
+    );
+    assert_eq!(
+        JSON::Object(candidates(&[0xfffe])),
+        json!({ "u16": 65534, "i16": -2 })
+    );
+}
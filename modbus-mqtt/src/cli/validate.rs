@@ -0,0 +1,471 @@
+use crate::modbus::{
+    computed::Computed,
+    connection::ModbusProto,
+    connector,
+    register::{Register, RegisterType, WriteFunction},
+    schema,
+};
+use clap::Args;
+use serde::Serialize;
+use serde_json::{Map, Value as JSON};
+use std::{fmt, path::PathBuf};
+
+/// Modbus limits reads to 125 registers
+const MAX_READ: u32 = 125;
+/// Write Multiple Registers (FC16) is limited to 123 registers
+const MAX_WRITE: u32 = 123;
+/// Read/Write Multiple Registers (FC23) is limited to 121 registers written
+const MAX_READ_WRITE: u32 = 121;
+
+/// The sections of a connection config which hold inline registers, and the register type they imply
+const SECTIONS: &[(&str, Option<RegisterType>)] = &[
+    ("input", Some(RegisterType::Input)),
+    ("holding", Some(RegisterType::Holding)),
+    ("hold", Some(RegisterType::Holding)),
+    ("registers", None),
+];
+
+#[derive(Args, Debug)]
+pub struct ValidateArgs {
+    #[clap(long, help = "Print a JSON object per problem instead of a line")]
+    json: bool,
+
+    #[clap(
+        value_hint = clap::ValueHint::FilePath,
+        help = "Connection config to check, as published to `$prefix/$id/connect`"
+    )]
+    file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct SchemaArgs {
+    #[clap(
+        long,
+        help = "Print the schema of a register config instead of a connection config"
+    )]
+    register: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Problem {
+    level: Level,
+
+    /// Where in the config the problem is, e.g. `holding[3]`. Empty for the connection itself.
+    path: String,
+
+    message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        match self.path.as_str() {
+            "" => write!(f, "{level}: {}", self.message),
+            path => write!(f, "{level}: {path}: {}", self.message),
+        }
+    }
+}
+
+pub(super) async fn run(args: ValidateArgs) -> crate::Result<()> {
+    let contents = std::fs::read(&args.file)?;
+    let problems = match serde_json::from_slice(&contents) {
+        Ok(config) => validate(&config),
+        Err(error) => vec![Problem {
+            level: Level::Error,
+            path: String::new(),
+            message: format!("invalid JSON: {error}"),
+        }],
+    };
+
+    for problem in &problems {
+        if args.json {
+            println!("{}", serde_json::to_string(problem)?);
+        } else {
+            println!("{problem}");
+        }
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.level == Level::Error)
+        .count();
+    if !args.json {
+        println!(
+            "{}: {errors} errors, {} warnings",
+            args.file.display(),
+            problems.len() - errors
+        );
+    }
+
+    match errors {
+        0 => Ok(()),
+        _ => Err(format!("{} is not a valid connection config", args.file.display()).into()),
+    }
+}
+
+pub(super) async fn schema(args: SchemaArgs) -> crate::Result<()> {
+    let schema = match args.register {
+        true => schema::register(),
+        false => schema::connection(),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// A register which parsed, for checks across registers
+struct Parsed {
+    path: String,
+    name: String,
+    register_type: RegisterType,
+    start: u32,
+    end: u32,
+}
+
+/// Check a connection config, returning every problem found in it
+fn validate(config: &JSON) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut report = |level, path: &str, message: String| {
+        problems.push(Problem {
+            level,
+            path: path.to_owned(),
+            message,
+        })
+    };
+
+    let Some(fields) = config.as_object() else {
+        report(Level::Error, "", "expected a JSON object".into());
+        return problems;
+    };
+
+    unknown_fields(fields, &schema::connection_properties(), |message| {
+        report(Level::Warning, "", message)
+    });
+
+    let mut address_offset = 0;
+    match serde_json::from_value::<connector::Config>(config.clone()) {
+        Ok(config) if matches!(config.connection.settings, ModbusProto::Unknown) => report(
+            Level::Error,
+            "",
+            format!(
+                "unknown proto {}, this build supports: {}",
+                fields.get("proto").unwrap_or(&JSON::Null),
+                protos().join(", ")
+            ),
+        ),
+        Ok(config) => address_offset = config.connection.address_offset,
+        Err(error) => report(Level::Error, "", error.to_string()),
+    }
+
+    let mut parsed = vec![];
+    for &(section, register_type) in SECTIONS {
+        let Some(registers) = fields.get(section) else {
+            continue;
+        };
+        let Some(registers) = registers.as_array() else {
+            report(
+                Level::Error,
+                section,
+                "expected an array of registers".into(),
+            );
+            continue;
+        };
+
+        for (index, register) in registers.iter().enumerate() {
+            let mut path = format!("{section}[{index}]");
+            if let Some(JSON::String(name)) = register.get("name") {
+                path = format!("{path} ({name})");
+            }
+
+            let mut report = |level, message| report(level, &path, message);
+            if let Some(register) =
+                validate_register(register, register_type, address_offset, &mut report)
+            {
+                parsed.push(Parsed {
+                    path,
+                    name: register.path(),
+                    register_type: register.register_type,
+                    start: register.address as u32,
+                    end: register.address as u32 + register.size().max(1) as u32 - 1,
+                });
+            }
+        }
+    }
+
+    for (index, register) in parsed.iter().enumerate() {
+        for other in &parsed[..index] {
+            if other.name == register.name {
+                report(
+                    Level::Warning,
+                    &register.path,
+                    format!(
+                        "publishes to the same topic as {}, so only one will be monitored",
+                        other.path
+                    ),
+                );
+            }
+            if other.register_type == register.register_type
+                && other.start <= register.end
+                && register.start <= other.end
+            {
+                report(
+                    Level::Warning,
+                    &register.path,
+                    format!(
+                        "overlaps {} (addresses {}..={})",
+                        other.path, other.start, other.end
+                    ),
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check a register config, returning it if it's a valid Modbus register for checks across registers
+fn validate_register(
+    register: &JSON,
+    register_type: Option<RegisterType>,
+    address_offset: i8,
+    report: &mut impl FnMut(Level, String),
+) -> Option<Register> {
+    let Some(fields) = register.as_object() else {
+        report(Level::Error, "expected a register config object".into());
+        return None;
+    };
+
+    if fields.contains_key("expression") || fields.contains_key("expr") {
+        unknown_fields(fields, &schema::computed_properties(), |message| {
+            report(Level::Warning, message)
+        });
+        if let Err(error) = serde_json::from_value::<Computed>(register.clone()) {
+            report(Level::Error, error.to_string());
+        }
+        return None;
+    }
+
+    unknown_fields(fields, &schema::modbus_properties(), |message| {
+        report(Level::Warning, message)
+    });
+
+    // The type is matched against each shape of value in turn, which leaves serde with nothing better to say than that
+    // none matched
+    if let Some(value_type) = fields.get("type") {
+        if !value_type
+            .as_str()
+            .is_some_and(|value_type| schema::TYPES.contains(&value_type))
+        {
+            report(
+                Level::Error,
+                format!(
+                    "unknown type {value_type}, expected one of: {}",
+                    schema::TYPES.join(", ")
+                ),
+            );
+            return None;
+        }
+    }
+
+    let mut register = match serde_json::from_value::<Register>(register.clone()) {
+        Ok(register) => register,
+        Err(error) => {
+            report(Level::Error, error.to_string());
+            return None;
+        }
+    };
+
+    // As the connector does, the section a register is in takes precedence over its own `register_type`
+    if let Some(register_type) = register_type {
+        register.register_type = register_type;
+    }
+
+    let size = register.size() as u32;
+    if size == 0 {
+        report(Level::Error, "reads no registers".into());
+    } else if size > MAX_READ {
+        report(
+            Level::Error,
+            format!("spans {size} registers, but at most {MAX_READ} can be read at once"),
+        );
+    }
+
+    let address = register.address as i32 + address_offset as i32;
+    if address < 0 || address + size as i32 - 1 > u16::MAX as i32 {
+        report(
+            Level::Error,
+            format!(
+                "with the address offset of {address_offset}, addresses {address}..={} are out of range",
+                address + size as i32 - 1
+            ),
+        );
+    }
+
+    if register.writable {
+        if register.register_type == RegisterType::Input {
+            report(
+                Level::Warning,
+                "is writable, but input registers are read-only".into(),
+            );
+        }
+
+        let (function, limit) = match register.write_function {
+            Some(WriteFunction::Single) => ("single (fc06)", 1),
+            Some(WriteFunction::ReadWrite) => ("read_write (fc23)", MAX_READ_WRITE),
+            _ => ("multiple (fc16)", MAX_WRITE),
+        };
+        if size > limit {
+            report(
+                Level::Error,
+                format!("spans {size} registers, but {function} can write at most {limit}"),
+            );
+        }
+
+        if let (Some(min), Some(max)) = (register.min, register.max) {
+            if min > max {
+                report(Level::Error, format!("min {min} is greater than max {max}"));
+            }
+        }
+    }
+
+    if !register.flags.is_empty() && size != 1 {
+        report(
+            Level::Error,
+            "flags can only be set on single-register values".into(),
+        );
+    }
+    for (name, bit) in &register.flags {
+        if *bit >= 16 {
+            report(
+                Level::Error,
+                format!("bit {bit} of flag {name:?} is out of range"),
+            );
+        }
+    }
+
+    Some(register)
+}
+
+/// Report any fields which aren't among the schema's `properties`, suggesting those they may be typos of
+fn unknown_fields(fields: &Map<String, JSON>, properties: &JSON, mut report: impl FnMut(String)) {
+    let Some(properties) = properties.as_object() else {
+        return;
+    };
+
+    for field in fields
+        .keys()
+        .filter(|field| !properties.contains_key(*field))
+    {
+        let suggestion = properties
+            .keys()
+            .map(|known| (distance(field, known), known))
+            .filter(|&(distance, _)| distance <= 2)
+            .min();
+        report(match suggestion {
+            Some((_, known)) => format!("unknown field {field:?}, did you mean {known:?}?"),
+            None => format!("unknown field {field:?}, which will be ignored"),
+        });
+    }
+}
+
+/// The Levenshtein distance between two strings
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + (a != b) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// The protocols this build supports
+fn protos() -> Vec<&'static str> {
+    [
+        (cfg!(feature = "tcp"), "tcp"),
+        (cfg!(feature = "rtu"), "rtu"),
+        (cfg!(feature = "winet-s"), "winet-s"),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, proto)| enabled.then_some(proto))
+    .collect()
+}
+
+#[test]
+fn test_validate() {
+    use serde_json::json;
+
+    let problems = validate(&json!({
+        "proto": "tcp",
+        "host": "10.10.10.219",
+        "adress_offset": 1,
+        "holding": [
+            { "name": "mode", "address": 13049, "writable": true },
+            { "name": "limit", "address": 13048, "type": "u32", "scale": -1 },
+            { "name": "battery", "adress": 13051 },
+            { "name": "soc", "address": 13060, "type": "u61" },
+            { "name": "model", "address": 13070, "type": "string", "length": 200 },
+            { "name": "bits", "address": 13300, "type": "u32", "flags": { "standby": 16 } },
+        ],
+        "input": [
+            { "name": "mode", "address": 13049 },
+            { "address": 65535, "type": "u32" },
+            { "name": "total", "expression": "mode +", "units": "W" },
+        ],
+    }));
+
+    let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        problems,
+        [
+            r#"warning: unknown field "adress_offset", did you mean "address_offset"?"#,
+            "error: input[1]: with the address offset of 0, addresses 65535..=65536 are out of range",
+            r#"warning: input[2] (total): unknown field "units", did you mean "unit"?"#,
+            "error: input[2] (total): unexpected None",
+            r#"warning: holding[2] (battery): unknown field "adress", did you mean "address"?"#,
+            "error: holding[2] (battery): missing field `address`",
+            r#"error: holding[3] (soc): unknown type "u61", expected one of: u8, u16, u32, u48, u64, i8, s8, i16, s16, i32, s32, i48, s48, i64, s64, f32, f64, bcd16, bcd32, array, string"#,
+            "error: holding[4] (model): spans 200 registers, but at most 125 can be read at once",
+            "error: holding[5] (bits): flags can only be set on single-register values",
+            r#"error: holding[5] (bits): bit 16 of flag "standby" is out of range"#,
+            "warning: holding[0] (mode): publishes to the same topic as input[0] (mode), so only one will be monitored",
+            "warning: holding[1] (limit): overlaps holding[0] (mode) (addresses 13049..=13049)",
+        ]
+    );
+
+    assert_eq!(
+        validate(&json!({ "proto": "modbus" }))[0].to_string(),
+        format!(
+            r#"error: unknown proto "modbus", this build supports: {}"#,
+            protos().join(", ")
+        )
+    );
+    assert_eq!(
+        validate(&json!({ "proto": "tcp" }))[0].to_string(),
+        "error: missing field `host`"
+    );
+    assert!(validate(&json!({ "proto": "tcp", "host": "localhost", "registers": [] })).is_empty());
+}
+
+#[test]
+fn test_distance() {
+    assert_eq!(distance("adress", "address"), 1);
+    assert_eq!(distance("units", "unit"), 1);
+    assert_eq!(distance("", "abc"), 3);
+    assert_eq!(distance("kitten", "sitting"), 3);
+}
@@ -0,0 +1,77 @@
+use super::{from_str, hex, ConnectionArgs, ParseArgs};
+use crate::modbus::{
+    register::{RegisterType, WriteFunction},
+    write::{self, Status},
+};
+use clap::Args;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct WriteArgs {
+    #[clap(flatten)]
+    connection: ConnectionArgs,
+
+    #[clap(long, help = "Address of the holding register to write")]
+    address: u16,
+
+    #[clap(flatten)]
+    parse: ParseArgs,
+
+    #[clap(
+        long,
+        value_parser = from_str::<WriteFunction>,
+        help = "auto, single (fc06), multiple (fc16) or read_write (fc23) [default: auto]"
+    )]
+    function: Option<WriteFunction>,
+
+    #[clap(long, help = "Print the outcome as JSON instead of a table")]
+    json: bool,
+
+    #[clap(
+        allow_hyphen_values = true,
+        help = "Value to write, as it would be published to a register's `set` topic"
+    )]
+    value: String,
+}
+
+pub(super) async fn run(args: WriteArgs) -> crate::Result<()> {
+    let register = args.parse.register(
+        args.address,
+        RegisterType::Holding,
+        json!({
+            "writable": true,
+            "verify": true,
+            "write_function": args.function,
+        }),
+    )?;
+
+    let modbus = args.connection.connect().await?;
+    let value = write::parse_value(args.value.as_bytes());
+    let outcome =
+        write::write_with_timeout(&register, value, &modbus, args.connection.timeout).await;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&outcome)?);
+    } else {
+        let status = serde_json::to_value(outcome.status)?;
+        println!("{:<10} {}", "status", status.as_str().unwrap_or_default());
+        for (label, words) in [
+            ("written", &outcome.written),
+            ("read back", &outcome.read_back),
+        ] {
+            if let Some(words) = words {
+                println!(
+                    "{:<10} {:<24}  {}",
+                    label,
+                    hex(words).join(" "),
+                    register.parse_words(words)
+                );
+            }
+        }
+    }
+
+    match outcome.status {
+        Status::Ok => Ok(()),
+        _ => Err(outcome.error.unwrap_or_default().into()),
+    }
+}
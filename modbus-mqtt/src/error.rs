@@ -9,11 +9,21 @@ pub enum Error {
     #[error(transparent)]
     MQTTOptionError(#[from] rumqttc::OptionError),
 
+    // The MQTT client and connection errors are boxed, as they are much larger than the rest
     #[error(transparent)]
-    MQTTClientError(#[from] rumqttc::ClientError),
+    MQTTClientError(Box<rumqttc::ClientError>),
 
     #[error(transparent)]
-    MQTTConnectionError(#[from] rumqttc::ConnectionError),
+    MQTTConnectionError(Box<rumqttc::ConnectionError>),
+
+    #[error(transparent)]
+    MQTTv5OptionError(#[from] rumqttc::v5::OptionError),
+
+    #[error(transparent)]
+    MQTTv5ClientError(Box<rumqttc::v5::ClientError>),
+
+    #[error(transparent)]
+    MQTTv5ConnectionError(Box<rumqttc::v5::ConnectionError>),
 
     #[error(transparent)]
     InvalidSocketAddr(#[from] std::net::AddrParseError),
@@ -43,6 +53,27 @@ pub enum Error {
     Unknown,
 }
 
+impl From<rumqttc::ClientError> for Error {
+    fn from(error: rumqttc::ClientError) -> Self {
+        Self::MQTTClientError(Box::new(error))
+    }
+}
+impl From<rumqttc::ConnectionError> for Error {
+    fn from(error: rumqttc::ConnectionError) -> Self {
+        Self::MQTTConnectionError(Box::new(error))
+    }
+}
+impl From<rumqttc::v5::ClientError> for Error {
+    fn from(error: rumqttc::v5::ClientError) -> Self {
+        Self::MQTTv5ClientError(Box::new(error))
+    }
+}
+impl From<rumqttc::v5::ConnectionError> for Error {
+    fn from(error: rumqttc::v5::ConnectionError) -> Self {
+        Self::MQTTv5ConnectionError(Box::new(error))
+    }
+}
+
 impl From<String> for Error {
     fn from(s: String) -> Self {
         Self::Other(s.into())
@@ -1,5 +1,6 @@
 mod shutdown;
 
+pub mod cli;
 pub mod modbus;
 pub mod mqtt;
 pub mod server;
@@ -1,6 +1,6 @@
 use clap::Parser;
-use modbus_mqtt::{server, Result};
-use rumqttc::MqttOptions;
+use modbus_mqtt::{cli, mqtt, server, Result};
+use std::path::PathBuf;
 use tokio::select;
 use url::Url;
 
@@ -9,9 +9,13 @@ use url::Url;
     name = "modbus-mqtt",
     version,
     author,
-    about = "A bridge between Modbus and MQTT"
+    about = "A bridge between Modbus and MQTT",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<cli::Command>,
+
     #[clap(
         env = "MQTT_URL",
         // validator = "is_mqtt_url",
@@ -20,13 +24,82 @@ struct Cli {
         help = "Pass the topic prefix as the URL path"
     )]
     url: Url,
+
+    #[clap(
+        long,
+        env = "MQTT_BUFFER_SIZE",
+        default_value_t = 1000,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Maximum number of publishes to buffer while the MQTT broker is unreachable"
+    )]
+    buffer_size: usize,
+
+    #[clap(
+        long,
+        env = "MQTT_BUFFER_FILE",
+        value_hint = clap::ValueHint::FilePath,
+        help = "File to spill publishes to when the buffer is full and to save them to on shutdown, to be replayed once connected"
+    )]
+    buffer_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        env = "MQTT_BUFFER_FILE_SIZE",
+        default_value_t = 100_000,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Maximum number of publishes to keep in the buffer file, beyond which the oldest are dropped"
+    )]
+    buffer_file_size: usize,
+
+    #[clap(
+        long,
+        env = "MQTT_CA_FILE",
+        value_hint = clap::ValueHint::FilePath,
+        help = "PEM file of CA certificates to verify the MQTT broker against, instead of the system's"
+    )]
+    ca_file: Option<PathBuf>,
+
+    #[clap(
+        long,
+        env = "MQTT_CLIENT_CERT",
+        value_hint = clap::ValueHint::FilePath,
+        requires = "client_key",
+        help = "PEM file of the certificate to authenticate to the MQTT broker with"
+    )]
+    client_cert: Option<PathBuf>,
+
+    #[clap(
+        long,
+        env = "MQTT_CLIENT_KEY",
+        value_hint = clap::ValueHint::FilePath,
+        requires = "client_cert",
+        help = "PEM file of the private key for --client-cert"
+    )]
+    client_key: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let Cli {
+        command,
+        mut url,
+        buffer_size,
+        buffer_file,
+        buffer_file_size,
+        ca_file,
+        client_cert,
+        client_key,
+    } = Cli::parse();
+
+    if let Some(command) = command {
+        // Keep stdout for the command's output
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+        return command.run().await;
+    }
 
-    let Cli { mut url } = Cli::parse();
+    tracing_subscriber::fmt::init();
 
     let mut prefix = url
         .path()
@@ -34,29 +107,27 @@ async fn main() -> Result<()> {
         .trim_end_matches('/')
         .to_owned();
 
-    let options: MqttOptions = match url.clone().try_into() {
-        Ok(options) => options,
-        Err(rumqttc::OptionError::ClientId) => {
-            let client_id = format!("{}-{}", env!("CARGO_PKG_NAME"), {
-                use rand::distributions::Alphanumeric;
-                use rand::{thread_rng, Rng};
-
-                thread_rng()
-                    .sample_iter(&Alphanumeric)
-                    .take(6)
-                    .map(char::from)
-                    .collect::<String>()
-            });
-
-            let url = url
-                .query_pairs_mut()
-                .append_pair("client_id", &client_id)
-                .finish()
-                .clone();
-            url.try_into()?
-        }
-        Err(other) => return Err(other.into()),
-    };
+    if !url.query_pairs().any(|(key, _)| key == "client_id") {
+        let client_id = format!("{}-{}", env!("CARGO_PKG_NAME"), {
+            use rand::distributions::Alphanumeric;
+            use rand::{thread_rng, Rng};
+
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(6)
+                .map(char::from)
+                .collect::<String>()
+        });
+
+        url.query_pairs_mut().append_pair("client_id", &client_id);
+    }
+
+    let mut options = mqtt::Options::try_from(url)?;
+    options.set_tls(&mqtt::TlsOptions {
+        ca_file,
+        client_cert,
+        client_key,
+    })?;
 
     if prefix.is_empty() {
         prefix = env!("CARGO_PKG_NAME").into();
@@ -85,7 +156,14 @@ async fn main() -> Result<()> {
         ctrl_c.await;
     };
 
-    server::run(prefix, options, shutdown).await?;
+    let buffer = mqtt::BufferOptions {
+        capacity: buffer_size,
+        file: buffer_file,
+        file_capacity: buffer_file_size,
+        ..Default::default()
+    };
+
+    server::run(prefix, options, buffer, shutdown).await?;
 
     Ok(())
 }
@@ -95,3 +173,12 @@ fn verify_cli() {
     use clap::CommandFactory;
     Cli::command().debug_assert()
 }
+
+#[test]
+fn test_buffer_sizes() {
+    assert_eq!(Cli::parse_from(["test"]).buffer_size, 1000);
+    for arg in ["--buffer-size", "--buffer-file-size"] {
+        assert!(Cli::try_parse_from(["test", arg, "0"]).is_err());
+        assert!(Cli::try_parse_from(["test", arg, "1"]).is_ok());
+    }
+}
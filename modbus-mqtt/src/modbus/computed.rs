@@ -0,0 +1,470 @@
+//! Virtual registers whose values are computed from other registers on the same connection.
+//!
+//! Rather than reading from the device, a computed register watches the published values of the registers named in
+//! its expression and publishes a new value whenever one of them changes.
+
+use crate::mqtt::{self, Payload, Scopable};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// How deeply parentheses, function calls and negations may be nested in an expression, so that parsing and evaluating
+/// it can't overflow the stack
+const MAX_DEPTH: usize = 32;
+
+/// Maximum number of tokens in an expression, which bounds how long chains of operators can be for the same reason
+const MAX_TOKENS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Computed {
+    pub name: String,
+
+    #[serde(alias = "expr")]
+    pub expression: Expression,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+
+    // Number of decimal places to round the computed value to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<u8>,
+}
+
+impl Computed {
+    pub fn path(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Evaluate the expression, returning `None` if any input is unknown or the arithmetic fails.
+    fn evaluate(&self, values: &HashMap<String, Decimal>) -> Option<Decimal> {
+        let value = self.expression.ast.evaluate(values)?;
+        Some(match self.precision {
+            Some(precision) => value.round_dp_with_strategy(
+                precision.into(),
+                rust_decimal::RoundingStrategy::MidpointAwayFromZero,
+            ),
+            None => value,
+        })
+    }
+}
+
+pub struct Monitor {
+    mqtt: mqtt::Handle,
+    computed: Computed,
+}
+
+impl Monitor {
+    /// `mqtt` is expected to be scoped to the connection's `registers` topic, under which the inputs are published.
+    pub fn new(computed: Computed, mqtt: mqtt::Handle) -> Monitor {
+        Monitor { mqtt, computed }
+    }
+
+    pub async fn run(self) {
+        tokio::spawn(async move {
+            let inputs = self.computed.expression.variables();
+            let mut values: HashMap<String, Decimal> = HashMap::new();
+
+            let mut updates = match self.mqtt.subscribe_under("+").await {
+                Ok(updates) => updates,
+                Err(error) => {
+                    warn!(?error, name = self.computed.name, "unable to watch inputs");
+                    return;
+                }
+            };
+            let mut mqtt = self
+                .mqtt
+                .scoped(self.computed.path())
+                .with_content_type("application/json");
+            if let Some(ref unit) = self.computed.unit {
+                mqtt = mqtt.with_user_property("unit", unit);
+            }
+
+            while let Some(Payload { topic, bytes, .. }) = updates.recv().await {
+                // `unwrap()` is safe because topics always contain at least the prefix and the register name
+                let input = topic.rsplit('/').next().unwrap();
+                if input == self.computed.name || !inputs.iter().any(|i| i == input) {
+                    continue;
+                }
+
+                match parse_value(&bytes) {
+                    Some(value) => values.insert(input.to_owned(), value),
+                    None => values.remove(input),
+                };
+
+                let value = serde_json::to_string(&self.computed.evaluate(&values)).unwrap();
+                debug!(name = self.computed.name, %value, input, "computed");
+
+                if let Err(error) = mqtt.publish(value).await {
+                    warn!(?error);
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Parse a published register value, which is either a bare number or an envelope with a `value` field.
+fn parse_value(bytes: &[u8]) -> Option<Decimal> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Published {
+        Plain(Decimal),
+        Envelope { value: Decimal },
+    }
+
+    match serde_json::from_slice(bytes).ok()? {
+        Published::Plain(value) | Published::Envelope { value } => Some(value),
+    }
+}
+
+/// An arithmetic expression over register names, supporting `+`, `-`, `*`, `/`, parentheses, and the functions
+/// `min(a, b, ...)`, `max(a, b, ...)` and `abs(a)`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    source: String,
+    ast: Node,
+}
+
+impl Expression {
+    /// Names of the registers which the expression depends on
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables = vec![];
+        self.ast.collect_variables(&mut variables);
+        variables.sort();
+        variables.dedup();
+        variables
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let ast = Parser::new(&source)?.parse()?;
+        Ok(Self { source, ast })
+    }
+}
+
+impl From<Expression> for String {
+    fn from(expression: Expression) -> Self {
+        expression.source
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Node {
+    Number(Decimal),
+    Variable(String),
+    Negate(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Function {
+    Min,
+    Max,
+    Abs,
+}
+
+impl Node {
+    fn evaluate(&self, values: &HashMap<String, Decimal>) -> Option<Decimal> {
+        match self {
+            Node::Number(n) => Some(*n),
+            Node::Variable(name) => values.get(name).copied(),
+            Node::Negate(node) => Some(-node.evaluate(values)?),
+            Node::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(values)?, rhs.evaluate(values)?);
+                match op {
+                    '+' => lhs.checked_add(rhs),
+                    '-' => lhs.checked_sub(rhs),
+                    '*' => lhs.checked_mul(rhs),
+                    '/' => lhs.checked_div(rhs),
+                    _ => unreachable!("parser only produces known operators"),
+                }
+            }
+            Node::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate(values))
+                    .collect::<Option<Vec<_>>>()?;
+                match function {
+                    Function::Min => args.into_iter().min(),
+                    Function::Max => args.into_iter().max(),
+                    Function::Abs => args.first().map(|arg| arg.abs()),
+                }
+            }
+        }
+    }
+
+    fn collect_variables(&self, variables: &mut Vec<String>) {
+        match self {
+            Node::Number(_) => {}
+            Node::Variable(name) => variables.push(name.clone()),
+            Node::Negate(node) => node.collect_variables(variables),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.collect_variables(variables);
+                rhs.collect_variables(variables);
+            }
+            Node::Call(_, args) => args.iter().for_each(|arg| arg.collect_variables(variables)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Decimal),
+    Ident(String),
+    Symbol(char),
+}
+
+/// A recursive descent parser over the usual precedence levels: sums, products, unary negation, then atoms.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, String> {
+        let mut tokens = vec![];
+        let mut chars = source.chars().peekable();
+
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c.is_ascii_digit() || c == '.' {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+                let number =
+                    Decimal::from_str(&number).map_err(|_| format!("invalid number {number:?}"))?;
+                tokens.push(Token::Number(number));
+            } else if c.is_alphabetic() || c == '_' {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            } else if "+-*/(),".contains(c) {
+                tokens.push(Token::Symbol(c));
+                chars.next();
+            } else {
+                return Err(format!("unexpected character {c:?}"));
+            }
+        }
+
+        if tokens.len() > MAX_TOKENS {
+            return Err(format!("expression is too long, over {MAX_TOKENS} tokens"));
+        }
+
+        Ok(Self {
+            tokens,
+            position: 0,
+            depth: 0,
+        })
+    }
+
+    fn parse(mut self) -> Result<Node, String> {
+        let node = self.sum()?;
+        match self.next() {
+            None => Ok(node),
+            Some(token) => Err(format!("unexpected {token:?}")),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            other => Err(format!("expected {symbol:?}, found {other:?}")),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+        while let Some(&Token::Symbol(op @ ('+' | '-'))) = self.peek() {
+            self.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while let Some(&Token::Symbol(op @ ('*' | '/'))) = self.peek() {
+            self.next();
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    /// Parse something nested within the current node, such as the contents of parentheses.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Node, String>) -> Result<Node, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("expression is nested more than {MAX_DEPTH} deep"));
+        }
+        self.depth += 1;
+        let node = parse(self);
+        self.depth -= 1;
+        node
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if let Some(Token::Symbol('-')) = self.peek() {
+            self.next();
+            return Ok(Node::Negate(Box::new(self.nested(Self::unary)?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Symbol('(')) => {
+                let node = self.nested(Self::sum)?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::Symbol('(')) => {
+                let function = match name.as_str() {
+                    "min" => Function::Min,
+                    "max" => Function::Max,
+                    "abs" => Function::Abs,
+                    _ => return Err(format!("unknown function {name:?}")),
+                };
+                self.next();
+
+                let mut args = vec![self.nested(Self::sum)?];
+                while let Some(Token::Symbol(',')) = self.peek() {
+                    self.next();
+                    args.push(self.nested(Self::sum)?);
+                }
+                self.expect(')')?;
+
+                if function == Function::Abs && args.len() != 1 {
+                    return Err("abs() takes exactly one argument".into());
+                }
+                Ok(Node::Call(function, args))
+            }
+            Some(Token::Ident(name)) => Ok(Node::Variable(name)),
+            other => Err(format!("unexpected {other:?}")),
+        }
+    }
+}
+
+#[test]
+fn parse_computed_config() {
+    use serde_json::json;
+    let computed = serde_json::from_value::<Computed>(json!({
+        "name": "net_power",
+        "expr": "pv_power - export_power",
+    }))
+    .unwrap();
+
+    assert_eq!(
+        computed.expression.variables(),
+        ["export_power", "pv_power"]
+    );
+    assert_eq!(
+        serde_json::to_value(&computed).unwrap(),
+        json!({
+            "name": "net_power",
+            "expression": "pv_power - export_power",
+        })
+    );
+
+    for invalid in ["", "a +", "(a", "a b", "foo(a)", "abs(a, b)", "a % b"] {
+        let result = serde_json::from_value::<Computed>(json!({
+            "name": "invalid",
+            "expression": invalid,
+        }));
+        assert!(result.is_err(), "{invalid:?} should be rejected");
+    }
+}
+
+#[test]
+fn test_expression_limits() {
+    let parse = |source: String| Expression::try_from(source);
+
+    let nested = |depth| "(".repeat(depth) + "a" + &")".repeat(depth);
+    assert!(parse(nested(MAX_DEPTH)).is_ok());
+    let error = parse(nested(MAX_DEPTH + 1)).unwrap_err();
+    assert!(error.contains("nested"), "{error}");
+    assert!(parse("-".repeat(MAX_DEPTH + 1) + "a").is_err());
+    assert!(parse("abs(".repeat(MAX_DEPTH + 1) + "a" + &")".repeat(MAX_DEPTH + 1)).is_err());
+
+    // Far deeper nesting is rejected rather than overflowing the stack
+    assert!(parse(nested(100_000)).is_err());
+    assert!(parse("-".repeat(100_000) + "a").is_err());
+
+    // Long chains of operators don't nest, but are limited in length
+    assert!(parse(vec!["a"; 100].join(" + ")).is_ok());
+    let error = parse(vec!["a"; MAX_TOKENS].join(" + ")).unwrap_err();
+    assert!(error.contains("too long"), "{error}");
+}
+
+#[test]
+fn test_evaluate_expression() {
+    let values: HashMap<String, Decimal> = [
+        ("battery_voltage", Decimal::new(512, 1)),
+        ("battery_current", Decimal::new(-25, 1)),
+        ("pv_power", Decimal::from(3000)),
+        ("export_power", Decimal::from(1200)),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v))
+    .collect();
+
+    let evaluate = |expression: &str| {
+        Expression::try_from(expression.to_owned())
+            .unwrap()
+            .ast
+            .evaluate(&values)
+    };
+
+    assert_eq!(
+        evaluate("pv_power - export_power"),
+        Some(Decimal::from(1800))
+    );
+    assert_eq!(
+        evaluate("battery_voltage * battery_current"),
+        Some(Decimal::from(-128))
+    );
+    assert_eq!(
+        evaluate("-(pv_power + 2 * export_power) / 100"),
+        Some(Decimal::from(-54))
+    );
+    assert_eq!(
+        evaluate("max(0, battery_current) + abs(min(battery_current, 0))"),
+        Some(Decimal::new(25, 1))
+    );
+    assert_eq!(evaluate("pv_power / 0"), None);
+    assert_eq!(evaluate("pv_power + unknown"), None);
+}
+
+#[test]
+fn test_parse_value() {
+    assert_eq!(parse_value(b"12.5"), Some(Decimal::new(125, 1)));
+    assert_eq!(
+        parse_value(br#"{"value": 12.5, "quality": "good"}"#),
+        Some(Decimal::new(125, 1))
+    );
+    assert_eq!(parse_value(br#"{"value": null, "quality": "bad"}"#), None);
+    assert_eq!(parse_value(b"null"), None);
+}
@@ -1,17 +1,19 @@
 use super::Word;
-use crate::modbus::{self, register};
+use crate::modbus::{self, computed, register, rpc, schedule};
 use crate::mqtt::Scopable;
 use crate::Error;
 use rust_decimal::prelude::Zero;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot, watch};
 use tokio_modbus::client::{rtu, tcp, Context as ModbusClient};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::{mqtt, shutdown::Shutdown};
 
-use super::register::RegisterType;
+use super::register::{RegisterType, WriteFunction};
 
 pub(crate) async fn run(
     config: Config,
@@ -20,13 +22,21 @@ pub(crate) async fn run(
 ) -> crate::Result<Handle> {
     let (connection_is_ready, mut is_connection_ready) = watch::channel(());
     let (mut tx, mut rx) = mpsc::channel(32);
-    let handle = Handle { tx: tx.clone() };
+    let handle = Handle {
+        tx: tx.clone(),
+        read_only: config.read_only,
+    };
 
     tokio::spawn(async move {
         // Can unwrap because if MQTT handler is bad, we have nothing to do here.
         mqtt.publish("connecting").await.unwrap();
 
         let address_offset = config.address_offset;
+        let payload_format = config.payload_format;
+        let read_only = config.read_only;
+        let rpc_writes = config.rpc_writes;
+        let write_function = config.write_function;
+        let schedule = schedule::Schedule::new(config.schedule.clone(), config.timezone);
 
         const MAX_WAIT: usize = 35;
         let mut current_wait = 1;
@@ -39,8 +49,17 @@ pub(crate) async fn run(
                     mqtt.publish("connected").await.unwrap();
 
                     let mut conn = Connection {
-                        address_offset,
-                        client,
+                        device: Device {
+                            client,
+                            address_offset,
+                            write_function,
+                            mask_write_unsupported: false,
+                        },
+                        payload_format,
+                        read_only,
+                        rpc_writes,
+                        schedule: schedule.clone(),
+                        registers: HashMap::new(),
                         mqtt: mqtt.clone(),
                         shutdown: shutdown.clone(), // Important, so that we can publish "disconnected" below
                         rx,
@@ -87,9 +106,40 @@ pub(crate) async fn run(
     Ok(handle)
 }
 
+/// Connect to a device without MQTT, for one-off commands such as those of the CLI. The connection is closed once the
+/// returned handle is dropped.
+pub(crate) async fn connect(config: &Config) -> crate::Result<Handle> {
+    let mut device = Device {
+        client: config.settings.connect(config.unit).await?,
+        address_offset: config.address_offset,
+        write_function: config.write_function,
+        mask_write_unsupported: false,
+    };
+
+    let (tx, mut rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        while let Some(cmd) = rx.recv().await {
+            if let Err(error) = device.process_command(cmd).await {
+                error!(?error, "Modbus connection failed");
+                break;
+            }
+        }
+    });
+
+    Ok(Handle {
+        tx,
+        read_only: config.read_only,
+    })
+}
+
 struct Connection {
-    client: ModbusClient,
-    address_offset: i8,
+    device: Device,
+    payload_format: register::PayloadFormat,
+    read_only: bool,
+    rpc_writes: bool,
+    schedule: schedule::Schedule,
+    // Channels to request writes from the monitors of each register, by path
+    registers: HashMap<String, mpsc::Sender<mqtt::Payload>>,
     mqtt: mqtt::Handle,
     shutdown: Shutdown,
     rx: mpsc::Receiver<Command>,
@@ -99,13 +149,85 @@ struct Connection {
 #[derive(Debug)]
 pub struct Handle {
     tx: mpsc::Sender<Command>,
+    read_only: bool,
 }
 
 impl Handle {
+    /// Whether the connection refuses all writes
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Write registers with the connection's `write_function`, returning the words written (or, for FC23, the words
+    /// read back).
     pub async fn write_register(&self, address: u16, data: Vec<Word>) -> crate::Result<Vec<Word>> {
+        self.write(None, address, data).await
+    }
+
+    /// Write registers with a specific function, or the connection's `write_function` if `None`.
+    pub async fn write_with(
+        &self,
+        function: Option<WriteFunction>,
+        address: u16,
+        data: Vec<Word>,
+    ) -> crate::Result<Vec<Word>> {
+        self.write(function, address, data).await
+    }
+
+    /// Write a single register with FC06
+    pub async fn write_single_register(&self, address: u16, word: Word) -> crate::Result<()> {
+        self.write(Some(WriteFunction::Single), address, vec![word])
+            .await
+            .map(|_| ())
+    }
+
+    /// Write registers with FC16
+    pub async fn write_multiple_registers(
+        &self,
+        address: u16,
+        data: Vec<Word>,
+    ) -> crate::Result<()> {
+        self.write(Some(WriteFunction::Multiple), address, data)
+            .await
+            .map(|_| ())
+    }
+
+    /// Change only some bits of a holding register, setting it to `(current & and_mask) | (or_mask & !and_mask)`.
+    ///
+    /// Uses Mask Write Register (FC22), falling back to reading the register and writing it back with `function` (or the
+    /// connection's `write_function` if `None`) if the device doesn't support it.
+    pub async fn mask_write_register(
+        &self,
+        function: Option<WriteFunction>,
+        address: u16,
+        and_mask: Word,
+        or_mask: Word,
+    ) -> crate::Result<()> {
+        if self.read_only {
+            return Err("connection is read-only".into());
+        }
+
         let (tx, rx) = oneshot::channel();
         self.tx
-            .send(Command::Write(address, data, tx))
+            .send(Command::MaskWrite(function, address, and_mask, or_mask, tx))
+            .await
+            .map_err(|_| Error::SendError)?;
+        rx.await.map_err(|_| Error::RecvError)?.map(|_| ())
+    }
+
+    async fn write(
+        &self,
+        function: Option<WriteFunction>,
+        address: u16,
+        data: Vec<Word>,
+    ) -> crate::Result<Vec<Word>> {
+        if self.read_only {
+            return Err("connection is read-only".into());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Write(function, address, data, tx))
             .await
             .map_err(|_| Error::SendError)?;
         rx.await.map_err(|_| Error::RecvError)?
@@ -144,31 +266,82 @@ impl Handle {
 
 type Response = oneshot::Sender<crate::Result<Vec<Word>>>;
 
+/// How long a scheduled write waits for the register's monitor to accept it, such as while it's busy with a slow read
+const SCHEDULED_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 enum Command {
     Read(RegisterType, u16, u8, Response),
-    Write(u16, Vec<Word>, Response),
+    Write(Option<WriteFunction>, u16, Vec<Word>, Response),
+    MaskWrite(Option<WriteFunction>, u16, Word, Word, Response),
 }
 
 impl Connection {
     pub async fn run(&mut self) -> crate::Result<()> {
         let mut registers_rx = register::subscribe(&self.mqtt).await?;
+        let mut rpc_rx = self.mqtt.subscribe_under(rpc::REQUEST_TOPIC).await?;
+
+        let mut next = self.schedule.next_after(chrono::Utc::now());
+        self.schedule.publish(&self.mqtt, next.as_ref()).await?;
+
+        // Computed registers only see the values which are published, so note which registers aren't to warn about
+        // computed registers which use them, whichever is configured first
+        let mut unpublished = HashSet::new();
+        let mut computed_inputs: HashMap<String, Vec<String>> = HashMap::new();
 
         loop {
             select! {
-                Some(cmd) = self.rx.recv() => { self.process_command(cmd).await?; },
+                Some(cmd) = self.rx.recv() => { self.device.process_command(cmd).await?; },
 
-                Some(register) = registers_rx.recv() => {
-                    debug!(?register);
+                Some(definition) = registers_rx.recv() => {
+                    debug!(?definition);
                     let mqtt = self.mqtt.scoped("registers");
-                    let modbus = self.handle();
-                    register::Monitor::new(
-                        register,
-                        mqtt,
-                        modbus,
-                    )
-                    .run()
-                    .await;
+                    match definition {
+                        register::Definition::Modbus(mut register) => {
+                            register.payload_format.get_or_insert(self.payload_format);
+                            let path = register.path();
+                            if register.publish_raw {
+                                unpublished.remove(&path);
+                            } else if unpublished.insert(path.clone()) {
+                                for (computed, inputs) in &computed_inputs {
+                                    if inputs.contains(&path) {
+                                        warn_unpublished_input(computed, &path);
+                                    }
+                                }
+                            }
+                            let modbus = self.handle();
+                            let monitor = register::Monitor::new(*register, mqtt, modbus);
+                            self.registers.insert(path, monitor.commands());
+                            monitor.run().await;
+                        }
+                        register::Definition::Computed(computed) => {
+                            let inputs = computed.expression.variables();
+                            for input in inputs.iter().filter(|input| unpublished.contains(*input)) {
+                                warn_unpublished_input(&computed.name, input);
+                            }
+                            computed_inputs.insert(computed.name.clone(), inputs);
+                            computed::Monitor::new(computed, mqtt).run().await;
+                        }
+                    }
+                },
+
+                Some(request) = rpc_rx.recv() => {
+                    // Requests are executed through a handle, which needs this loop to be free to process commands
+                    let (mqtt, modbus) = (self.mqtt.clone(), self.handle());
+                    tokio::spawn(rpc::handle(request, mqtt, modbus, self.rpc_writes));
+                },
+
+                _ = tokio::time::sleep(next.as_ref().map(schedule::Next::wait).unwrap_or_default()), if next.is_some() => {
+                    match next.take() {
+                        Some(due) if due.is_due() => {
+                            for action in due.actions {
+                                self.execute(action);
+                            }
+                            next = self.schedule.next_after(due.at.into());
+                            self.schedule.publish(&self.mqtt, next.as_ref()).await?;
+                        }
+                        not_due => next = not_due,
+                    }
                 },
 
                 _ = self.shutdown.recv() => {
@@ -178,12 +351,43 @@ impl Connection {
         }
     }
 
+    /// Request a scheduled write from the register's monitor, just as if it had been published to `$register/set`. If
+    /// it can't be requested, the reason is published to `schedule/error`.
+    fn execute(&self, action: schedule::Entry) {
+        info!(name = ?action.name, register = %action.register, value = %action.value, "scheduled write");
+
+        let commands = self.registers.get(&action.register).cloned();
+        let mqtt = self.mqtt.clone();
+        // The monitor may be waiting on this loop to process its reads, so wait on it in turn elsewhere
+        tokio::spawn(async move {
+            let requested = request_write(commands, &action, SCHEDULED_WRITE_TIMEOUT).await;
+            if let Err(error) = requested {
+                warn!(error, register = %action.register, "unable to request scheduled write");
+                if let Err(error) = schedule::publish_error(&mqtt, &action, error).await {
+                    warn!(?error, "unable to publish scheduled write error");
+                }
+            }
+        });
+    }
+
     fn handle(&self) -> Handle {
         Handle {
             tx: self.tx.clone(),
+            read_only: self.read_only,
         }
     }
+}
 
+/// The device end of a connection, which executes commands one at a time.
+struct Device {
+    client: ModbusClient,
+    address_offset: i8,
+    write_function: WriteFunction,
+    // Set once the device has refused a FC22 mask write, so that later ones go straight to read-modify-write
+    mask_write_unsupported: bool,
+}
+
+impl Device {
     // TODO: if we get a new register definition for an existing register, how do we avoid redundant (and possibly
     // conflicting) tasks? Should MQTT component only allow one subscriber per topic filter, replacing the old one
     // when it gets a new subscribe request?
@@ -235,18 +439,17 @@ impl Connection {
                         .await,
                 )
             }
-            Command::Write(address, data, tx) => {
+            Command::Write(function, address, data, tx) => {
+                let address = self.adjust_address(address);
+                let function = function.unwrap_or(self.write_function);
+                (tx, self.write(function, address, data).await)
+            }
+            Command::MaskWrite(function, address, and_mask, or_mask, tx) => {
                 let address = self.adjust_address(address);
+                let function = function.unwrap_or(self.write_function);
                 (
                     tx,
-                    self.client
-                        .read_write_multiple_registers(
-                            address,
-                            data.len() as u16,
-                            address,
-                            &data[..],
-                        )
-                        .await,
+                    self.mask_write(function, address, and_mask, or_mask).await,
                 )
             }
         };
@@ -282,6 +485,115 @@ impl Connection {
 
         Ok(())
     }
+
+    /// Write registers, returning the words written (or, for FC23, the words read back).
+    async fn write(
+        &mut self,
+        function: WriteFunction,
+        address: u16,
+        data: Vec<Word>,
+    ) -> std::io::Result<Vec<Word>> {
+        use tokio_modbus::prelude::{Reader, Writer};
+
+        match (function.resolve(data.len()), &data[..]) {
+            (WriteFunction::Single, &[word]) => self
+                .client
+                .write_single_register(address, word)
+                .await
+                .map(|_| data),
+            (WriteFunction::Single, _) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("FC06 writes exactly one register, not {}", data.len()),
+            )),
+            (WriteFunction::Multiple | WriteFunction::Auto, _) => self
+                .client
+                .write_multiple_registers(address, &data)
+                .await
+                .map(|_| data),
+            (WriteFunction::ReadWrite, _) => {
+                self.client
+                    .read_write_multiple_registers(address, data.len() as u16, address, &data[..])
+                    .await
+            }
+        }
+    }
+
+    /// Mask write a register, returning the word written if it had to be read and written back.
+    async fn mask_write(
+        &mut self,
+        function: WriteFunction,
+        address: u16,
+        and_mask: Word,
+        or_mask: Word,
+    ) -> std::io::Result<Vec<Word>> {
+        use tokio_modbus::prelude::{Reader, Writer};
+
+        if !self.mask_write_unsupported {
+            match self
+                .client
+                .masked_write_register(address, and_mask, or_mask)
+                .await
+            {
+                // Devices without FC22 respond with "Illegal function". Any other exception is about this particular
+                // write, so is returned as is.
+                Err(error) if is_illegal_function(&error) => {
+                    debug!(
+                        ?error,
+                        "mask write refused, falling back to read-modify-write"
+                    );
+                    self.mask_write_unsupported = true;
+                }
+                result => return result.map(|_| vec![]),
+            }
+        }
+
+        let current = self.client.read_holding_registers(address, 1).await?;
+        let Some(&current) = current.first() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "no register value returned",
+            ));
+        };
+        let word = mask(current, and_mask, or_mask);
+        self.write(function, address, vec![word]).await
+    }
+}
+
+/// Whether a request was refused with an "Illegal function" exception, meaning the device doesn't support the function.
+fn is_illegal_function(error: &std::io::Error) -> bool {
+    // tokio_modbus reports exception responses as `Other`, and doesn't export the exception type to check against
+    error.kind() == std::io::ErrorKind::Other && error.to_string().ends_with("Illegal function")
+}
+
+/// Hand a scheduled write to the register's monitor (if it has one), waiting up to `timeout` for it to be accepted.
+async fn request_write(
+    commands: Option<mpsc::Sender<mqtt::Payload>>,
+    action: &schedule::Entry,
+    timeout: Duration,
+) -> Result<(), &'static str> {
+    let commands = commands.ok_or("unknown register")?;
+    let command = mqtt::Payload {
+        bytes: action.value.to_string().into(),
+        topic: format!("registers/{}/set", action.register),
+        properties: Default::default(),
+    };
+    match tokio::time::timeout(timeout, commands.send(command)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(_)) => Err("register is no longer monitored"),
+        Err(_) => Err("timed out waiting for the register to accept the write"),
+    }
+}
+
+fn warn_unpublished_input(computed: &str, input: &str) {
+    warn!(
+        computed,
+        input, "computed register's input has `publish_raw: false`, so it will never be computed"
+    );
+}
+
+/// The result of a mask write, as defined by the Modbus specification for FC22
+fn mask(current: Word, and_mask: Word, or_mask: Word) -> Word {
+    (current & and_mask) | (or_mask & !and_mask)
 }
 
 #[derive(Debug, Deserialize)]
@@ -298,6 +610,30 @@ pub(crate) struct Config {
 
     #[serde(default)]
     pub address_offset: i8,
+
+    // Default format of published register values, which registers may override
+    #[serde(default)]
+    pub payload_format: register::PayloadFormat,
+
+    // Refuse all writes to the device, whether to registers or via RPC
+    #[serde(default)]
+    pub read_only: bool,
+
+    // Allow writes via RPC, which bypass registers' `writable`, `min`, `max` and `allowed_values`
+    #[serde(default)]
+    pub rpc_writes: bool,
+
+    // Modbus function to write registers with, which registers may override
+    #[serde(default)]
+    pub write_function: WriteFunction,
+
+    // Writes to make at scheduled times
+    #[serde(default)]
+    pub schedule: Vec<schedule::Entry>,
+
+    // The timezone which scheduled times are in
+    #[serde(default)]
+    pub timezone: schedule::Timezone,
 }
 
 #[derive(Deserialize)]
@@ -549,3 +885,72 @@ fn parse_complete_rtu_connect_config() {
         } if tty == "/dev/ttyUSB0"
     ),);
 }
+
+#[test]
+fn test_mask() {
+    // Example from the Modbus specification
+    assert_eq!(mask(0x12, 0xf2, 0x25), 0x17);
+
+    // Setting and clearing a single bit
+    assert_eq!(mask(0b1010, !0b0100, 0b0100), 0b1110);
+    assert_eq!(mask(0b1010, !0b0010, 0), 0b1000);
+}
+
+#[test]
+fn test_is_illegal_function() {
+    use std::io::{Error, ErrorKind};
+
+    // As tokio_modbus formats exception responses
+    let exception = |message| Error::other(message);
+    assert!(is_illegal_function(&exception(
+        "Modbus function 22: Illegal function"
+    )));
+    assert!(!is_illegal_function(&exception(
+        "Modbus function 22: Illegal data address"
+    )));
+    assert!(!is_illegal_function(&Error::new(
+        ErrorKind::TimedOut,
+        "Illegal function"
+    )));
+}
+
+#[tokio::test]
+async fn test_request_write() {
+    let action: schedule::Entry = serde_json::from_value(serde_json::json!({
+        "at": "00:30",
+        "register": "forced_power",
+        "value": 5000,
+    }))
+    .unwrap();
+    let timeout = Duration::from_millis(10);
+
+    let (tx, mut rx) = mpsc::channel(1);
+    request_write(Some(tx.clone()), &action, timeout)
+        .await
+        .unwrap();
+    let command = rx.recv().await.unwrap();
+    assert_eq!(command.topic, "registers/forced_power/set");
+    assert_eq!(&command.bytes[..], b"5000");
+
+    // A busy monitor is waited on rather than the write being dropped, up to the timeout
+    request_write(Some(tx.clone()), &action, timeout)
+        .await
+        .unwrap();
+    let waiting = tokio::spawn({
+        let (tx, action) = (tx.clone(), action.clone());
+        async move { request_write(Some(tx), &action, Duration::from_secs(5)).await }
+    });
+    rx.recv().await.unwrap();
+    assert_eq!(waiting.await.unwrap(), Ok(()));
+    let error = request_write(Some(tx.clone()), &action, timeout).await;
+    assert_eq!(
+        error,
+        Err("timed out waiting for the register to accept the write")
+    );
+
+    drop(rx);
+    let error = request_write(Some(tx), &action, timeout).await;
+    assert_eq!(error, Err("register is no longer monitored"));
+    let error = request_write(None, &action, timeout).await;
+    assert_eq!(error, Err("unknown register"));
+}
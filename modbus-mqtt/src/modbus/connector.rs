@@ -1,10 +1,11 @@
-use crate::modbus::{connection, register};
+use crate::modbus::{connection, register, sunspec};
 use crate::mqtt::{Payload, Scopable};
 use crate::{mqtt, shutdown::Shutdown};
 use serde::Deserialize;
 use serde_json::value::Value as JSON;
+use std::time::Duration;
 use tokio::select;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /*
 NOTE: Should this be a connection _registry_ of sorts which also restarts connections which die?
@@ -13,6 +14,9 @@ NOTE: Should this be a connection _registry_ of sorts which also restarts connec
 /// The topic filter under the prefix to look for connection configs
 const TOPIC: &str = "+/connect";
 
+/// How long to wait for a device to describe its SunSpec models before giving up on discovering its registers
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// Responsible for monitoring MQTT topic for connection configs
 pub struct Connector {
     mqtt: mqtt::Handle,
@@ -35,11 +39,14 @@ impl Connector {
 
         loop {
             select! {
-                Some(Payload { bytes, topic }) = new_connection.recv() => {
+                Some(Payload { bytes, topic, .. }) = new_connection.recv() => {
                     // `unwrap()` is safe here because of the shape of valid topics and the fact that we are subcribed
                     // to a topic under a prefix.
                     let connection_id = topic.rsplit('/').nth_back(1).unwrap();
-                    let mqtt = self.mqtt.scoped(connection_id);
+                    let mqtt = self
+                        .mqtt
+                        .scoped(connection_id)
+                        .with_user_property("connection", connection_id);
 
                     debug!(?connection_id, ?bytes, ?topic, "Received connection config");
 
@@ -86,12 +93,13 @@ async fn connect(config: Config, mqtt: mqtt::Handle, shutdown: Shutdown) -> crat
     #[allow(deprecated)]
     let Config {
         connection: settings,
+        profile,
         input,
         holding,
         registers,
     } = config;
 
-    let _ = connection::run(settings, mqtt.clone(), shutdown).await?;
+    let modbus = connection::run(settings, mqtt.clone(), shutdown).await?;
 
     // TODO: consider waiting 1 second before sending the registers to MQTT, to ensure that the connection is listening.
 
@@ -107,30 +115,79 @@ async fn connect(config: Config, mqtt: mqtt::Handle, shutdown: Shutdown) -> crat
     ] {
         use register::*;
         let mqtt = mqtt.scoped("registers");
-        for reg in registers {
-            if let Ok(mut reg) = serde_json::from_value::<Register>(reg) {
-                reg.register_type = match reg_type {
-                    Type::Holding => RegisterType::Holding,
-                    Type::Input => RegisterType::Input,
-                    Type::Unchanged => reg.register_type,
-                };
-
-                let json = serde_json::to_vec(&reg).unwrap(); // unwrap() should be fine because we JUST deserialized it successfully
-                mqtt.publish_under(format!("{}/config", reg.path()), json)
-                    .await?;
+        for (index, reg) in registers.into_iter().enumerate() {
+            match serde_json::from_value::<Definition>(reg) {
+                Ok(Definition::Modbus(mut reg)) => {
+                    reg.register_type = match reg_type {
+                        Type::Holding => RegisterType::Holding,
+                        Type::Input => RegisterType::Input,
+                        Type::Unchanged => reg.register_type,
+                    };
+
+                    publish_register(&mqtt, &Definition::Modbus(reg)).await?;
+                }
+                Ok(computed) => publish_register(&mqtt, &computed).await?,
+                Err(error) => warn!(
+                    index,
+                    %error,
+                    "Ignoring invalid register config, which `modbus-mqtt validate` can explain"
+                ),
             }
         }
     }
 
+    // Discovery takes many requests to the device, so is left to run alongside the configured registers rather than
+    // holding up other connections (or shutdown) until it's done
+    if let Some(Profile::SunSpec) = profile {
+        tokio::spawn(discover_sunspec(modbus, mqtt));
+    }
+
     Ok(())
 }
 
+async fn discover_sunspec(modbus: connection::Handle, mqtt: mqtt::Handle) {
+    let discovered = match tokio::time::timeout(DISCOVERY_TIMEOUT, sunspec::discover(&modbus)).await
+    {
+        Ok(discovered) => discovered,
+        Err(_) => Err("timed out".into()),
+    };
+
+    match discovered {
+        Ok(registers) => {
+            info!(count = registers.len(), "Discovered SunSpec registers");
+            let mqtt = mqtt.scoped("registers");
+            for reg in registers {
+                let reg = register::Definition::Modbus(Box::new(reg));
+                if let Err(error) = publish_register(&mqtt, &reg).await {
+                    warn!(?error, "unable to publish SunSpec register");
+                }
+            }
+        }
+        Err(error) => {
+            error!(?error, "SunSpec discovery failed");
+            if let Err(error) = mqtt.publish_under("last_error", format!("{error:?}")).await {
+                warn!(?error, "unable to publish SunSpec discovery error");
+            }
+        }
+    }
+}
+
+async fn publish_register(mqtt: &mqtt::Handle, reg: &register::Definition) -> crate::Result<()> {
+    let json = serde_json::to_vec(reg).unwrap(); // unwrap() should be fine because registers always serialize
+    mqtt.publish_under(format!("{}/config", reg.path()), json)
+        .await
+}
+
 /// Wrapper around `modbus::connection::Config` that can include some registers inline, which the connector will
 /// re-publish to the appropriate topic once the connection is established.
 #[derive(Debug, Deserialize)]
-struct Config {
+pub(crate) struct Config {
     #[serde(flatten)]
-    connection: connection::Config,
+    pub(crate) connection: connection::Config,
+
+    // Discover registers from the device itself, in addition to any defined inline
+    #[serde(default)]
+    profile: Option<Profile>,
 
     // Allow registers to be defined inline, but capture them as raw JSON so that if they have incorrect schema, we can
     // still establish the Modbus connection. Valid registers will be re-emitted as individual register configs to MQTT,
@@ -147,3 +204,22 @@ struct Config {
     #[serde(default)]
     registers: Vec<JSON>,
 }
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Profile {
+    SunSpec,
+}
+
+#[test]
+fn parse_sunspec_profile_config() {
+    use serde_json::json;
+    let config = serde_json::from_value::<Config>(json!({
+        "proto": "tcp",
+        "host": "1.1.1.1",
+        "profile": "sunspec",
+    }))
+    .unwrap();
+
+    assert_eq!(config.profile, Some(Profile::SunSpec));
+}
@@ -0,0 +1,264 @@
+//! Integration of power readings into an energy total, for devices which don't expose their own counters.
+
+use crate::mqtt::{self, Payload, Scopable};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{error::TryRecvError, Receiver};
+use tracing::{debug, warn};
+
+/// How long to wait for a previously retained total once connected to the broker
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Integrate {
+    // Name of the sibling register topic to publish the total to. Defaults to `${register}_energy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset: Option<Reset>,
+
+    // Readings further apart than this are not integrated across, so that outages don't produce a spike in energy
+    #[serde(with = "humantime_serde", default = "default_max_gap")]
+    pub max_gap: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reset {
+    /// Reset the total to zero at local midnight
+    Daily,
+}
+
+fn default_max_gap() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// The persisted state of a total, retained under `$total/state` so that it survives restarts.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    total: Decimal,
+    date: NaiveDate,
+}
+
+/// Publishes a running energy total for a register's readings.
+#[derive(Debug)]
+pub(crate) struct Integrator {
+    mqtt: mqtt::Handle,
+    total: Total,
+
+    /// Until the previous total has been restored (or found not to exist), readings are integrated but not published,
+    /// so that a retained total isn't overwritten.
+    restoring: Option<Restore>,
+}
+
+#[derive(Debug)]
+struct Restore {
+    retained: Receiver<Payload>,
+
+    /// When to give up waiting for a retained state, counted from when the broker was last seen to be connected
+    deadline: Option<Instant>,
+}
+
+impl Integrator {
+    /// `mqtt` is expected to be scoped to the topic the total is published to.
+    pub async fn restore(config: Integrate, mqtt: mqtt::Handle) -> Integrator {
+        let restoring = match mqtt.subscribe_under("state").await {
+            Ok(retained) => Some(Restore {
+                retained,
+                deadline: None,
+            }),
+            Err(error) => {
+                warn!(?error, "unable to restore energy total");
+                None
+            }
+        };
+
+        Integrator {
+            mqtt,
+            total: Total {
+                config,
+                total: Decimal::ZERO,
+                date: today(),
+                last: None,
+            },
+            restoring,
+        }
+    }
+
+    /// Integrate a new reading and publish the resulting total, once the previous total has been restored.
+    pub async fn record(&mut self, value: Decimal) -> crate::Result<()> {
+        self.total.update(Instant::now(), today(), value);
+
+        if !self.try_restore().await {
+            return Ok(());
+        }
+        let total = self.total.total.normalize();
+
+        let state = serde_json::to_vec(&State {
+            total,
+            date: self.total.date,
+        })?;
+        self.mqtt.publish_retained_under("state", state).await?;
+        self.mqtt
+            .publish_retained(serde_json::to_string(&total)?)
+            .await
+    }
+
+    /// Pick up the retained state, if it has arrived, returning whether restoring is done. The broker sends retained
+    /// publishes as soon as it is subscribed to, so if none has arrived a while after connecting there isn't one.
+    async fn try_restore(&mut self) -> bool {
+        let Some(ref mut restore) = self.restoring else {
+            return true;
+        };
+
+        match restore.retained.try_recv() {
+            Ok(Payload { bytes, .. }) => match serde_json::from_slice::<State>(&bytes) {
+                Ok(state) => {
+                    debug!(total = %state.total, date = %state.date, "restored energy total");
+                    self.total.restore(state);
+                }
+                Err(error) => warn!(?error, "ignoring invalid energy state"),
+            },
+            Err(TryRecvError::Empty) if self.mqtt.is_connected() => {
+                let deadline = *restore
+                    .deadline
+                    .get_or_insert_with(|| Instant::now() + RESTORE_TIMEOUT);
+                if Instant::now() < deadline {
+                    return false;
+                }
+                debug!("no energy total to restore");
+            }
+            Err(TryRecvError::Empty) => {
+                restore.deadline = None;
+                return false;
+            }
+            Err(TryRecvError::Disconnected) => {}
+        }
+
+        // Our own retained states would otherwise be sent straight back to us
+        self.restoring = None;
+        if let Err(error) = self.mqtt.scoped("state").unsubscribe().await {
+            warn!(?error, "unable to unsubscribe from energy state");
+        }
+        true
+    }
+}
+
+/// A trapezoidal integral of readings over time, in the reading's unit multiplied by hours (e.g. W → Wh).
+#[derive(Debug)]
+struct Total {
+    config: Integrate,
+    total: Decimal,
+    date: NaiveDate,
+    last: Option<(Instant, Decimal)>,
+}
+
+impl Total {
+    /// Add a previously persisted total to the energy integrated since starting, unless it has since been reset.
+    fn restore(&mut self, state: State) {
+        if self.config.reset == Some(Reset::Daily) && state.date != self.date {
+            return;
+        }
+        self.total += state.total;
+    }
+
+    fn update(&mut self, now: Instant, today: NaiveDate, value: Decimal) -> Decimal {
+        if self.config.reset == Some(Reset::Daily) && today != self.date {
+            self.total = Decimal::ZERO;
+        }
+        self.date = today;
+
+        if let Some((then, previous)) = self.last {
+            let elapsed = now.duration_since(then);
+            if elapsed <= self.config.max_gap {
+                // Multiply before dividing to avoid accumulating rounding errors from repeating decimals
+                let millis = Decimal::from(elapsed.as_millis() as u64);
+                self.total += (previous + value) * millis / Decimal::from(2 * 3_600_000);
+            }
+        }
+        self.last = Some((now, value));
+
+        self.total.normalize()
+    }
+}
+
+fn today() -> NaiveDate {
+    chrono::Local::now().date_naive()
+}
+
+#[test]
+fn parse_integrate_config() {
+    use serde_json::json;
+    let config = serde_json::from_value::<Integrate>(json!({
+        "reset": "daily",
+    }))
+    .unwrap();
+
+    assert_eq!(
+        config,
+        Integrate {
+            name: None,
+            reset: Some(Reset::Daily),
+            max_gap: Duration::from_secs(300),
+        }
+    );
+}
+
+#[test]
+fn test_integrate() {
+    let mut total = Total {
+        config: Integrate {
+            name: None,
+            reset: Some(Reset::Daily),
+            max_gap: Duration::from_secs(300),
+        },
+        total: Decimal::from(100),
+        date: NaiveDate::from_ymd_opt(2022, 9, 1).unwrap(),
+        last: None,
+    };
+
+    let start = Instant::now();
+    let day = total.date;
+    let at = |secs| start + Duration::from_secs(secs);
+
+    // The first reading has nothing to integrate against
+    assert_eq!(
+        total.update(at(0), day, Decimal::from(1000)),
+        Decimal::from(100)
+    );
+
+    // 1kW → 2kW over 1 minute averages 1.5kW, which is 25Wh
+    assert_eq!(
+        total.update(at(60), day, Decimal::from(2000)),
+        Decimal::from(125)
+    );
+
+    // A gap longer than `max_gap` is skipped
+    assert_eq!(
+        total.update(at(1000), day, Decimal::from(2000)),
+        Decimal::from(125)
+    );
+
+    // A restored total is added to what was integrated before it arrived, unless it's from a previous day
+    total.restore(State {
+        total: Decimal::from(1000),
+        date: day,
+    });
+    assert_eq!(total.total, Decimal::from(1125));
+    total.restore(State {
+        total: Decimal::from(1000),
+        date: day.pred_opt().unwrap(),
+    });
+    assert_eq!(total.total, Decimal::from(1125));
+    total.total = Decimal::from(125);
+
+    // A new day starts from zero
+    let tomorrow = day.succ_opt().unwrap();
+    assert_eq!(
+        total.update(at(1180), tomorrow, Decimal::from(1600)),
+        Decimal::from(90)
+    );
+}
@@ -1,6 +1,15 @@
+pub mod computed;
 pub mod connection;
 pub mod connector;
+mod energy;
 pub mod register;
+mod rpc;
+mod schedule;
+pub(crate) mod schema;
+mod stats;
+mod sunspec;
+mod watchdog;
+pub(crate) mod write;
 
 pub use connection::Handle;
 
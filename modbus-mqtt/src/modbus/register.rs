@@ -1,10 +1,18 @@
-use super::Word;
+use super::{
+    computed::Computed,
+    energy::{Integrate, Integrator},
+    stats::{Aggregator, Window},
+    watchdog::{self, Watchdog},
+    write, Word,
+};
 use crate::mqtt::{self, Payload, Scopable};
+use rust_decimal::{Decimal, MathematicalOps};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{collections::BTreeMap, time::Duration};
 use tokio::{
+    select,
     sync::mpsc,
-    time::{interval, MissedTickBehavior},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
 };
 use tracing::{debug, warn};
 
@@ -12,67 +20,269 @@ pub struct Monitor {
     mqtt: mqtt::Handle,
     modbus: super::Handle,
     register: Register,
+    energy: Option<mqtt::Handle>,
+
+    // Writes requested other than via MQTT, such as by the connection's schedule
+    commands: (mpsc::Sender<Payload>, mpsc::Receiver<Payload>),
 }
 
 impl Monitor {
     pub fn new(register: Register, mqtt: mqtt::Handle, modbus: super::Handle) -> Monitor {
+        let mqtt = mqtt.with_content_type("application/json");
+
+        let energy = register.integrate.as_ref().map(|integrate| {
+            let energy = mqtt.scoped(
+                integrate
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("{}_energy", register.path())),
+            );
+            match register.unit {
+                // Integrating over hours turns e.g. W into Wh
+                Some(ref unit) => energy.with_user_property("unit", format!("{unit}h")),
+                None => energy,
+            }
+        });
+
+        let mut scoped = mqtt.scoped(register.path());
+        if let Some(ref unit) = register.unit {
+            scoped = scoped.with_user_property("unit", unit);
+        }
+
         Monitor {
-            mqtt: mqtt.scoped(register.path()),
+            mqtt: scoped,
             modbus,
             register,
+            energy,
+            commands: mpsc::channel(8),
         }
     }
 
-    pub async fn run(self) {
+    /// A channel for requesting writes, just as if published to `$register/set`
+    pub fn commands(&self) -> mpsc::Sender<Payload> {
+        self.commands.0.clone()
+    }
+
+    pub async fn run(mut self) {
         tokio::spawn(async move {
+            let mut integrator = match (&self.register.integrate, &self.energy) {
+                (Some(integrate), Some(mqtt)) => {
+                    Some(Integrator::restore(integrate.clone(), mqtt.clone()).await)
+                }
+                _ => None,
+            };
+
+            let mut aggregators: Vec<Aggregator> = self
+                .register
+                .aggregate
+                .iter()
+                .map(|window| Aggregator::new(*window, &self.mqtt))
+                .collect();
+
+            // A reading is stale once the next one is due, so there's no point in the broker holding on to it
+            let values = self.mqtt.with_message_expiry(self.register.interval);
+
             let mut interval = interval(self.register.interval);
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
+            // Only registers which could be written listen for writes, so as not to subscribe to a topic for every
+            // register read. Failing to listen for them is no reason to stop reading the register.
+            let mut set = None;
+            if write::check_access(&self.register, self.modbus.is_read_only()).is_ok() {
+                match self.mqtt.subscribe_under("set").await {
+                    Ok(rx) => set = Some(rx),
+                    Err(error) => warn!(?error, "unable to subscribe to writes"),
+                }
+            }
+
+            let mut watchdog = match self.register.refresh_interval {
+                Some(refresh_interval) => Some(Watchdog::new(refresh_interval, &self.mqtt).await),
+                None => None,
+            };
+
             loop {
-                interval.tick().await;
-                if let Ok(words) = self.read().await {
-                    let value = self.register.parse_words(&words);
-                    let value = serde_json::to_string(&value).unwrap();
-
-                    debug!(
-                        address=%self.register.address,
-                        "type"=?self.register.register_type,
-                        %value,
-                        raw=%format!("{:04x?}", &words),
-                    );
-
-                    if let Err(error) = self.mqtt.publish(value).await {
-                        warn!(?error);
-                        break;
+                let next_window = aggregators.iter().map(|a| a.closes_at).min();
+                let next_refresh = watchdog.as_ref().and_then(|w| w.due_at);
+
+                select! {
+                    _ = interval.tick() => {},
+
+                    // Having written, fall through to reading the register so that its new value is published
+                    Some(request) = async { set.as_mut()?.recv().await } => self.command(request, watchdog.as_mut()).await,
+                    Some(request) = self.commands.1.recv() => self.command(request, watchdog.as_mut()).await,
+
+                    _ = sleep_until(next_refresh.unwrap_or_else(Instant::now)), if next_refresh.is_some() => {
+                        if let Some(ref mut watchdog) = watchdog {
+                            if let Some(value) = watchdog.command() {
+                                let outcome = write::write(&self.register, value, &self.modbus).await;
+                                debug!(address = self.register.address, ?outcome, "refresh");
+                                watchdog.record(&outcome).await;
+                            }
+                        }
+                        continue;
+                    }
+
+                    _ = sleep_until(next_window.unwrap_or_else(Instant::now)), if next_window.is_some() => {
+                        let now = Instant::now();
+                        for aggregator in &mut aggregators {
+                            if let Err(error) = aggregator.close_if_due(now).await {
+                                warn!(?error);
+                            }
+                        }
+                        continue;
+                    }
+                }
+
+                let reading = self.read_value().await;
+
+                let format = self.register.payload_format.unwrap_or_default();
+                if let Some(payload) = payload(format, &reading) {
+                    if self.register.publish_raw {
+                        if let Err(error) = values.publish(payload).await {
+                            warn!(?error);
+                            break;
+                        }
+                    }
+                }
+
+                let Ok(reading) =
+                    reading.and_then(|(_, value)| Ok(serde_json::from_value::<Decimal>(value)?))
+                else {
+                    continue;
+                };
+
+                for aggregator in &mut aggregators {
+                    aggregator.record(reading);
+                }
+
+                if let Some(ref mut integrator) = integrator {
+                    if let Err(error) = integrator.record(reading).await {
+                        warn!(?error, "unable to publish energy total");
                     }
                 }
             }
         });
     }
 
-    async fn read(&self) -> crate::Result<Vec<Word>> {
-        let Self { ref register, .. } = self;
-        match register.register_type {
-            RegisterType::Input => {
-                self.modbus
-                    .read_input_register(register.address, register.size())
-                    .await
-            }
-            RegisterType::Holding => {
-                self.modbus
-                    .read_holding_register(register.address, register.size())
-                    .await
+    /// Handle a request to set the register, which releases it from the watchdog (if it has one) when empty or `null`.
+    async fn command(&self, request: Payload, watchdog: Option<&mut Watchdog>) {
+        match watchdog {
+            Some(watchdog) if watchdog::is_release(&request.bytes) => watchdog.release().await,
+            Some(watchdog) => watchdog.record(&self.write(request).await).await,
+            None => {
+                self.write(request).await;
             }
         }
     }
+
+    /// Write the value requested to be set, publishing the outcome to `set/result`.
+    async fn write(&self, request: Payload) -> write::Outcome {
+        let value = write::parse_value(&request.bytes);
+
+        let outcome = write::write(&self.register, value, &self.modbus).await;
+        debug!(address = self.register.address, ?outcome, "write");
+
+        let payload = serde_json::to_string(&outcome).unwrap();
+        let published = match self
+            .mqtt
+            .respond(&request.properties, payload.clone())
+            .await
+        {
+            Ok(_) => self.mqtt.publish_under("set/result", payload).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = published {
+            warn!(?error, "unable to publish write result");
+        }
+
+        outcome
+    }
+
+    /// Read the register (and its scale, if any), returning the raw words along with the parsed value.
+    async fn read_value(&self) -> crate::Result<(Vec<Word>, serde_json::Value)> {
+        let words = self.read().await?;
+        let value = match self.read_scale().await {
+            None => self.register.parse_words(&words),
+            Some(scale) => self.register.parse_scaled_words(&words, scale?),
+        };
+
+        debug!(
+            address=%self.register.address,
+            "type"=?self.register.register_type,
+            %value,
+            raw=%format!("{:04x?}", &words),
+        );
+
+        Ok((words, value))
+    }
+
+    async fn read(&self) -> crate::Result<Vec<Word>> {
+        self.read_at(self.register.address, self.register.size())
+            .await
+    }
+
+    /// Read the current scale factor, if the register takes its scale from another register.
+    async fn read_scale(&self) -> Option<crate::Result<i16>> {
+        let address = self.register.scale_register()?;
+        let scale = self.read_at(address, 1).await.and_then(|words| {
+            words
+                .first()
+                .map(|&word| word as i16)
+                .ok_or_else(|| "no scale factor returned".into())
+        });
+        Some(scale)
+    }
+
+    async fn read_at(&self, address: u16, size: u8) -> crate::Result<Vec<Word>> {
+        match self.register.register_type {
+            RegisterType::Input => self.modbus.read_input_register(address, size).await,
+            RegisterType::Holding => self.modbus.read_holding_register(address, size).await,
+        }
+    }
+}
+
+/// The payload to publish for a reading, if any.
+fn payload(
+    format: PayloadFormat,
+    reading: &crate::Result<(Vec<Word>, serde_json::Value)>,
+) -> Option<String> {
+    match format {
+        PayloadFormat::Plain => reading
+            .as_ref()
+            .ok()
+            .map(|(_, value)| serde_json::to_string(value).unwrap()),
+        PayloadFormat::Envelope => {
+            let envelope = match reading {
+                Ok((words, value)) => Envelope {
+                    value: value.clone(),
+                    raw: Some(words.iter().map(|word| format!("{word:04x}")).collect()),
+                    ts: chrono::Utc::now(),
+                    quality: if value.is_null() {
+                        Quality::Bad
+                    } else {
+                        Quality::Good
+                    },
+                    error: None,
+                },
+                Err(error) => Envelope {
+                    value: serde_json::Value::Null,
+                    raw: None,
+                    ts: chrono::Utc::now(),
+                    quality: Quality::Bad,
+                    error: Some(error.to_string()),
+                },
+            };
+            Some(serde_json::to_string(&envelope).unwrap())
+        }
+    }
 }
 
-pub(crate) async fn subscribe(mqtt: &mqtt::Handle) -> crate::Result<mpsc::Receiver<Register>> {
+pub(crate) async fn subscribe(mqtt: &mqtt::Handle) -> crate::Result<mpsc::Receiver<Definition>> {
     let (tx, rx) = mpsc::channel(8);
     let mut registers = mqtt.subscribe_under("registers/+/config").await?;
 
     tokio::spawn(async move {
-        fn to_register(payload: &Payload) -> crate::Result<Register> {
+        fn to_register(payload: &Payload) -> crate::Result<Definition> {
             Ok(serde_json::from_slice(&payload.bytes)?)
         }
 
@@ -95,6 +305,89 @@ pub(crate) async fn subscribe(mqtt: &mqtt::Handle) -> crate::Result<mpsc::Receiv
     Ok(rx)
 }
 
+#[derive(Deserialize, Serialize, PartialEq, Eq, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// Publish the bare JSON value
+    #[default]
+    Plain,
+
+    /// Publish an object with the value, raw words, timestamp and quality. Read errors are published too.
+    Envelope,
+}
+
+/// The Modbus function used to write registers
+#[derive(Deserialize, Serialize, PartialEq, Eq, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteFunction {
+    /// Write Single Register (FC06) when writing one register, otherwise Write Multiple Registers (FC16)
+    #[default]
+    Auto,
+
+    /// Write Single Register (FC06)
+    #[serde(alias = "fc06")]
+    Single,
+
+    /// Write Multiple Registers (FC16), which some devices require even for a single register
+    #[serde(alias = "fc16")]
+    Multiple,
+
+    /// Read/Write Multiple Registers (FC23)
+    #[serde(alias = "fc23")]
+    ReadWrite,
+}
+
+impl WriteFunction {
+    /// Resolve `Auto` to the function to use for writing `count` registers
+    pub fn resolve(self, count: usize) -> WriteFunction {
+        match self {
+            WriteFunction::Auto if count == 1 => WriteFunction::Single,
+            WriteFunction::Auto => WriteFunction::Multiple,
+            function => function,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Envelope {
+    value: serde_json::Value,
+
+    // The words read from the device, as hex
+    raw: Option<Vec<String>>,
+
+    ts: chrono::DateTime<chrono::Utc>,
+
+    quality: Quality,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Quality {
+    Good,
+    Bad,
+}
+
+/// A register config, as published to `registers/$name/config`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Definition {
+    // Must come first, as any computed register config would otherwise fail as a `Register` for lack of an `address`
+    Computed(Computed),
+    Modbus(Box<Register>),
+}
+
+impl Definition {
+    pub fn path(&self) -> String {
+        match self {
+            Definition::Computed(computed) => computed.path(),
+            Definition::Modbus(register) => register.path(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Eq, Default, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum RegisterType {
@@ -107,8 +400,68 @@ pub enum RegisterType {
 #[serde(rename_all = "lowercase", default)]
 pub struct RegisterNumericAdjustment {
     pub scale: i8, // powers of 10 (0 = no adjustment, 1 = x10, -1 = /10)
-    pub offset: i8,
-    // precision: Option<u8>,
+
+    // Address of a signed 16-bit register (of the same register type) holding the scale, which takes the place of
+    // `scale` when set. This is how SunSpec devices expose scale factors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale_register: Option<u16>,
+
+    // Arbitrary decimal factors, applied after `scale`, for adjustments which aren't a power of 10
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multiplier: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divisor: Option<Decimal>,
+
+    pub offset: Decimal,
+
+    // Number of decimal places to round the final value to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub precision: Option<u8>,
+}
+
+impl RegisterNumericAdjustment {
+    /// 10 to the power of `scale`, or `None` if that's beyond what a `Decimal` can hold (beyond ±28).
+    fn scale_factor(&self) -> Option<Decimal> {
+        Some(Decimal::TEN.checked_powi(self.scale.into())?.normalize())
+    }
+
+    /// Returns `None` if the adjustment can't be applied (e.g. a zero divisor or an overflow).
+    fn apply(&self, value: Decimal) -> Option<Decimal> {
+        use rust_decimal::RoundingStrategy;
+
+        let mut value = value.checked_mul(self.scale_factor()?)?;
+
+        if let Some(multiplier) = self.multiplier {
+            value = value.checked_mul(multiplier)?;
+        }
+        if let Some(divisor) = self.divisor {
+            value = value.checked_div(divisor)?;
+        }
+
+        value = value.checked_add(self.offset)?;
+
+        if let Some(precision) = self.precision {
+            value = value
+                .round_dp_with_strategy(precision.into(), RoundingStrategy::MidpointAwayFromZero);
+        }
+
+        Some(value.normalize())
+    }
+
+    /// The inverse of `apply`, turning a value into the raw number to write. Returns `None` if the adjustment can't be
+    /// reversed (e.g. a zero multiplier or an overflow).
+    fn unapply(&self, value: Decimal) -> Option<Decimal> {
+        let mut value = value.checked_sub(self.offset)?;
+
+        if let Some(divisor) = self.divisor {
+            value = value.checked_mul(divisor)?;
+        }
+        if let Some(multiplier) = self.multiplier {
+            value = value.checked_div(multiplier)?;
+        }
+
+        Some(value.checked_div(self.scale_factor()?)?.normalize())
+    }
 }
 
 #[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -131,6 +484,14 @@ pub enum RegisterNumeric {
 
     F32,
     F64,
+
+    U48,
+    #[serde(alias = "s48")]
+    I48,
+
+    // Binary-coded decimal, one digit per nibble
+    Bcd16,
+    Bcd32,
 }
 
 impl RegisterNumeric {
@@ -140,12 +501,99 @@ impl RegisterNumeric {
         // Each Modbus register holds 16-bits, so count is half what the byte count would be
         match self {
             U8 | I8 => 1,
-            U16 | I16 => 1,
-            U32 | I32 | F32 => 2,
+            U16 | I16 | Bcd16 => 1,
+            U32 | I32 | F32 | Bcd32 => 2,
+            U48 | I48 => 3,
             U64 | I64 | F64 => 4,
         }
     }
 
+    /// Decode big-endian bytes into a number, before any adjustment is applied.
+    ///
+    /// Returns `None` if there are too few bytes, or they don't represent a valid number of this type (e.g. `NaN` or
+    /// an invalid BCD digit).
+    fn decode(&self, bytes: &[u8]) -> Option<Decimal> {
+        use rust_decimal::prelude::FromPrimitive;
+        use RegisterNumeric::*;
+
+        let bytes = bytes.get(..self.size() as usize * 2)?;
+
+        match self {
+            U8 => Some(Decimal::from(bytes[1])), // or is it 0?
+            I8 => Some(Decimal::from(bytes[1] as i8)),
+            U16 => Some(Decimal::from(u16::from_be_bytes(bytes.try_into().ok()?))),
+            I16 => Some(Decimal::from(i16::from_be_bytes(bytes.try_into().ok()?))),
+            U32 => Some(Decimal::from(u32::from_be_bytes(bytes.try_into().ok()?))),
+            I32 => Some(Decimal::from(i32::from_be_bytes(bytes.try_into().ok()?))),
+            U64 => Some(Decimal::from(u64::from_be_bytes(bytes.try_into().ok()?))),
+            I64 => Some(Decimal::from(i64::from_be_bytes(bytes.try_into().ok()?))),
+            U48 | I48 => {
+                let mut buf = [0u8; 8];
+                buf[2..].copy_from_slice(bytes);
+                let value = u64::from_be_bytes(buf);
+                if *self == I48 {
+                    // Shift the sign bit into place, then back again to sign-extend
+                    Some(Decimal::from(((value << 16) as i64) >> 16))
+                } else {
+                    Some(Decimal::from(value))
+                }
+            }
+            F32 => Decimal::from_f32(f32::from_be_bytes(bytes.try_into().ok()?)),
+            F64 => Decimal::from_f64(f64::from_be_bytes(bytes.try_into().ok()?)),
+            Bcd16 | Bcd32 => bytes
+                .iter()
+                .flat_map(|byte| [byte >> 4, byte & 0x0f])
+                .try_fold(0u64, |acc, digit| {
+                    (digit < 10).then(|| acc * 10 + digit as u64)
+                })
+                .map(Decimal::from),
+        }
+    }
+
+    /// Encode a number into big-endian bytes. Integer types round to the nearest whole number.
+    ///
+    /// Returns `None` if the number is out of range for this type.
+    fn encode(&self, value: Decimal) -> Option<Vec<u8>> {
+        use rust_decimal::prelude::ToPrimitive;
+        use rust_decimal::RoundingStrategy;
+        use RegisterNumeric::*;
+
+        let int = value.round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero);
+
+        Some(match self {
+            U8 => vec![0, int.to_u8()?],
+            I8 => vec![0, int.to_i8()? as u8],
+            U16 => int.to_u16()?.to_be_bytes().to_vec(),
+            I16 => int.to_i16()?.to_be_bytes().to_vec(),
+            U32 => int.to_u32()?.to_be_bytes().to_vec(),
+            I32 => int.to_i32()?.to_be_bytes().to_vec(),
+            U64 => int.to_u64()?.to_be_bytes().to_vec(),
+            I64 => int.to_i64()?.to_be_bytes().to_vec(),
+            U48 => {
+                let value = int.to_u64().filter(|&v| v < 1 << 48)?;
+                value.to_be_bytes()[2..].to_vec()
+            }
+            I48 => {
+                let value = int
+                    .to_i64()
+                    .filter(|&v| (-(1 << 47)..1 << 47).contains(&v))?;
+                value.to_be_bytes()[2..].to_vec()
+            }
+            F32 => value.to_f32()?.to_be_bytes().to_vec(),
+            F64 => value.to_f64()?.to_be_bytes().to_vec(),
+            Bcd16 | Bcd32 => {
+                let digits = self.size() as usize * 4;
+                let mut value = int.to_u64().filter(|&v| v < 10u64.pow(digits as u32))?;
+                let mut bytes = vec![0u8; digits / 2];
+                for byte in bytes.iter_mut().rev() {
+                    *byte = (value % 10) as u8 | ((value / 10 % 10) as u8) << 4;
+                    value /= 100;
+                }
+                bytes
+            }
+        })
+    }
+
     fn type_name(&self) -> String {
         format!("{:?}", *self).to_lowercase()
     }
@@ -155,6 +603,158 @@ impl RegisterNumeric {
 #[serde(tag = "type", rename = "string")]
 pub struct RegisterString {
     length: u8,
+
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    encoding: StringEncoding,
+
+    // Swap the two bytes within each register, independent of the register's `byte_order`
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    byte_swap: bool,
+
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    trim: StringTrim,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StringEncoding {
+    Ascii,
+    #[default]
+    Utf8,
+    Utf16be,
+    Utf16le,
+    Latin1,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StringTrim {
+    /// Keep the value exactly as read
+    None,
+
+    /// Remove trailing NUL characters, and pad with NUL when writing
+    #[default]
+    Nul,
+
+    /// Remove leading and trailing NUL and whitespace characters, and pad with spaces when writing
+    Whitespace,
+}
+
+impl RegisterString {
+    pub fn new(length: u8) -> Self {
+        Self {
+            length,
+            encoding: Default::default(),
+            byte_swap: false,
+            trim: Default::default(),
+        }
+    }
+
+    fn decode(&self, words: &[Word]) -> String {
+        let bytes: Vec<u8> = words
+            .iter()
+            .map(|word| {
+                if self.byte_swap {
+                    word.swap_bytes()
+                } else {
+                    *word
+                }
+            })
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+
+        let utf16 = |from_bytes: fn([u8; 2]) -> u16| {
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| from_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        };
+
+        let string = match self.encoding {
+            StringEncoding::Ascii => bytes
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii() {
+                        b as char
+                    } else {
+                        char::REPLACEMENT_CHARACTER
+                    }
+                })
+                .collect(),
+            StringEncoding::Utf8 => String::from_utf8_lossy(&bytes).into_owned(),
+            StringEncoding::Utf16be => utf16(u16::from_be_bytes),
+            StringEncoding::Utf16le => utf16(u16::from_le_bytes),
+            StringEncoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        };
+
+        match self.trim {
+            StringTrim::None => string,
+            StringTrim::Nul => string.trim_end_matches(char::from(0)).to_owned(),
+            StringTrim::Whitespace => string
+                .trim_matches(|c: char| c == char::from(0) || c.is_whitespace())
+                .to_owned(),
+        }
+    }
+
+    /// Encode a string into exactly `length` registers, padding as appropriate for the trim mode.
+    pub fn encode(&self, value: &str) -> crate::Result<Vec<Word>> {
+        let pad = match self.trim {
+            StringTrim::Whitespace => ' ',
+            StringTrim::None | StringTrim::Nul => char::from(0),
+        };
+
+        let mut bytes: Vec<u8> = match self.encoding {
+            StringEncoding::Utf8 => value.as_bytes().to_vec(),
+            StringEncoding::Ascii | StringEncoding::Latin1 => {
+                let max = if self.encoding == StringEncoding::Ascii {
+                    0x7f
+                } else {
+                    0xff
+                };
+                value
+                    .chars()
+                    .map(|c| {
+                        u8::try_from(c).ok().filter(|&b| b <= max).ok_or_else(|| {
+                            format!("{c:?} cannot be encoded as {:?}", self.encoding)
+                        })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            StringEncoding::Utf16be => value.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            StringEncoding::Utf16le => value.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        };
+
+        let capacity = self.length as usize * 2;
+        if bytes.len() > capacity {
+            return Err(format!(
+                "string of {} bytes does not fit in {} registers",
+                bytes.len(),
+                self.length
+            )
+            .into());
+        }
+
+        while bytes.len() < capacity {
+            match self.encoding {
+                StringEncoding::Utf16be => bytes.extend((pad as u16).to_be_bytes()),
+                StringEncoding::Utf16le => bytes.extend((pad as u16).to_le_bytes()),
+                _ => bytes.push(pad as u8),
+            }
+        }
+
+        Ok(bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .map(|word| {
+                if self.byte_swap {
+                    word.swap_bytes()
+                } else {
+                    word
+                }
+            })
+            .collect())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -220,17 +820,143 @@ impl RegisterValueType {
 
         match self {
             Numeric { of, .. } => of.size(),
-            String(RegisterString { length }) => *length,
-            Array(RegisterArray { of, count, .. }) => of.size() * count,
+            String(RegisterString { length, .. }) => *length,
+            // Saturates, as anything this large is beyond what can be read anyway
+            Array(RegisterArray { of, count, .. }) => of.size().saturating_mul(*count),
         }
     }
 }
 
-#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(transparent)]
-pub struct Swap(pub bool);
+/// The order in which the bytes of a value are laid out across its registers.
+///
+/// Each letter names a byte of the big-endian value, with `A` being the most significant, in the order they appear on
+/// the wire. For instance, `"ABCD"` is plain big-endian, `"CDAB"` swaps the two words of a 32-bit value, and
+/// `"BADC"` swaps the bytes within each word. The pattern is repeated across values longer than it, so `"CDAB"`
+/// applied to a 64-bit value swaps each pair of words.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteOrder(String);
+
+impl ByteOrder {
+    /// The position on the wire of each byte of the big-endian value
+    fn positions(&self) -> Vec<usize> {
+        (b'A'..)
+            .take(self.0.len())
+            .map(|letter| self.0.bytes().position(|b| b == letter).unwrap()) // validated in `TryFrom`
+            .collect()
+    }
+
+    /// The position on the wire of each byte of a value shorter than the pattern, such as the last word of a 48-bit
+    /// value. Where the pattern keeps those bytes among themselves it applies as is, otherwise the words stay in place
+    /// but the bytes within each are swapped as the pattern swaps them. So `"BADC"` still swaps the bytes of a 16-bit
+    /// value, while `"CDAB"` leaves it alone.
+    fn partial_positions(&self, len: usize) -> Vec<usize> {
+        let positions = self.positions();
+        if positions[..len].iter().all(|&pos| pos < len) {
+            return positions[..len].to_vec();
+        }
+
+        let pattern = self.0.as_bytes();
+        (0..len)
+            .map(|pos| match pattern.get(pos & !1..=pos | 1) {
+                Some(&[first, second]) if first > second && pos | 1 < len => pos ^ 1,
+                _ => pos,
+            })
+            .collect()
+    }
+
+    /// Re-order bytes from the wire into big-endian order.
+    pub fn apply(&self, bytes: &[u8]) -> Vec<u8> {
+        let positions = self.positions();
+
+        let mut chunks = bytes.chunks_exact(positions.len());
+        let mut ordered: Vec<u8> = chunks
+            .by_ref()
+            .flat_map(|chunk| positions.iter().map(|&pos| chunk[pos]))
+            .collect();
+        let remainder = chunks.remainder();
+        ordered.extend(
+            self.partial_positions(remainder.len())
+                .into_iter()
+                .map(|pos| remainder[pos]),
+        );
+        ordered
+    }
+
+    /// Re-order big-endian bytes into the order they appear on the wire. The inverse of `apply`.
+    pub fn unapply(&self, bytes: &[u8]) -> Vec<u8> {
+        let positions = self.positions();
+        let unapply = |positions: &[usize], chunk: &[u8]| {
+            let mut wire = vec![0; chunk.len()];
+            for (&pos, &byte) in positions.iter().zip(chunk) {
+                wire[pos] = byte;
+            }
+            wire
+        };
+
+        let mut chunks = bytes.chunks_exact(positions.len());
+        let mut wire: Vec<u8> = chunks
+            .by_ref()
+            .flat_map(|chunk| unapply(&positions, chunk))
+            .collect();
+        let remainder = chunks.remainder();
+        wire.extend(unapply(&self.partial_positions(remainder.len()), remainder));
+        wire
+    }
+
+    pub fn apply_words(&self, words: &[Word]) -> Vec<Word> {
+        Self::map_words(words, |bytes| self.apply(bytes))
+    }
+
+    pub fn unapply_words(&self, words: &[Word]) -> Vec<Word> {
+        Self::map_words(words, |bytes| self.unapply(bytes))
+    }
+
+    fn map_words(words: &[Word], f: impl Fn(&[u8]) -> Vec<u8>) -> Vec<Word> {
+        let bytes: Vec<u8> = words.iter().flat_map(|v| v.to_be_bytes()).collect();
+        f(&bytes)
+            .chunks_exact(2)
+            .map(|pair| Word::from_be_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.0.bytes().zip(b'A'..).all(|(b, letter)| b == letter)
+    }
+}
 
-trait IsDefault {
+impl Default for ByteOrder {
+    fn default() -> Self {
+        Self("ABCD".into())
+    }
+}
+
+impl TryFrom<String> for ByteOrder {
+    type Error = String;
+
+    fn try_from(order: String) -> Result<Self, Self::Error> {
+        let order = order.to_ascii_uppercase();
+        let mut letters: Vec<u8> = order.bytes().collect();
+        letters.sort_unstable();
+
+        if ![2, 4, 8].contains(&order.len()) || !letters.into_iter().eq((b'A'..).take(order.len()))
+        {
+            return Err(format!(
+                "invalid byte order {order:?}: expected a permutation of \"AB\", \"ABCD\", or \"ABCDEFGH\""
+            ));
+        }
+
+        Ok(Self(order))
+    }
+}
+
+impl From<ByteOrder> for String {
+    fn from(order: ByteOrder) -> Self {
+        order.0
+    }
+}
+
+pub(crate) trait IsDefault {
     fn is_default(&self) -> bool;
 }
 impl<T> IsDefault for T
@@ -243,31 +969,137 @@ where
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(from = "LegacyRegisterParse")]
 pub struct RegisterParse {
     #[serde(default, skip_serializing_if = "IsDefault::is_default")]
-    pub swap_bytes: Swap,
-
-    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
-    pub swap_words: Swap,
+    pub byte_order: ByteOrder,
 
     #[serde(flatten, skip_serializing_if = "IsDefault::is_default")]
     pub value_type: RegisterValueType,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Register {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub name: Option<String>,
+impl RegisterParse {
+    pub fn parse_words(&self, words: &[u16]) -> serde_json::Value {
+        self.value_type
+            .parse_words(&self.byte_order.apply_words(words))
+    }
 
-    pub address: u16,
+    /// Encode a value into the words to write, using `scale` in place of any configured scale.
+    pub fn encode_with_scale(
+        &self,
+        value: &serde_json::Value,
+        scale: Option<i8>,
+    ) -> crate::Result<Vec<Word>> {
+        let words = self.value_type.encode_with_scale(value, scale)?;
+        Ok(self.byte_order.unapply_words(&words))
+    }
+}
 
-    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
-    pub register_type: RegisterType,
+/// Accepts the `swap_bytes`/`swap_words` flags which pre-date `byte_order`, translating them when no explicit
+/// `byte_order` is given.
+#[derive(Deserialize)]
+struct LegacyRegisterParse {
+    #[serde(default)]
+    byte_order: Option<ByteOrder>,
 
-    #[serde(flatten, default, skip_serializing_if = "IsDefault::is_default")]
-    pub parse: RegisterParse,
+    #[serde(default)]
+    swap_bytes: bool,
 
-    #[serde(
+    #[serde(default)]
+    swap_words: bool,
+
+    #[serde(flatten)]
+    value_type: RegisterValueType,
+}
+
+impl From<LegacyRegisterParse> for RegisterParse {
+    fn from(legacy: LegacyRegisterParse) -> Self {
+        let byte_order = legacy.byte_order.unwrap_or_else(|| {
+            let order = match (legacy.swap_bytes, legacy.swap_words) {
+                (false, false) => "ABCD",
+                (true, false) => "BADC",
+                (false, true) => "CDAB",
+                (true, true) => "DCBA",
+            };
+            ByteOrder(order.into())
+        });
+
+        Self {
+            byte_order,
+            value_type: legacy.value_type,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Register {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    pub address: u16,
+
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    pub register_type: RegisterType,
+
+    #[serde(flatten, default, skip_serializing_if = "IsDefault::is_default")]
+    pub parse: RegisterParse,
+
+    // Unit of measurement of the published value. Informational only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+
+    // Accumulate readings into an energy total, published to a sibling register topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrate: Option<Integrate>,
+
+    // Windows over which to publish statistics of readings, under `$register/stats/$window`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aggregate: Vec<Window>,
+
+    // Defaults to the connection's `payload_format`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_format: Option<PayloadFormat>,
+
+    // Whether to publish each reading to the register topic, which can be disabled if only aggregates are wanted
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub publish_raw: bool,
+
+    // Whether the register may be written to by publishing to `$register/set`
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    pub writable: bool,
+
+    // Bounds on the values which may be written, in the same units as published values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<Decimal>,
+
+    // If not empty, the only values which may be written
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_values: Vec<serde_json::Value>,
+
+    // Names of the bits (0 being least significant) of a single-register value, so that each may be set on its own by
+    // publishing e.g. `{"standby": true}` to `$register/set`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub flags: BTreeMap<String, u8>,
+
+    // Defaults to the connection's `write_function`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_function: Option<WriteFunction>,
+
+    // Read the register back after writing to it, to confirm that the device accepted the value
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    pub verify: bool,
+
+    // Keep rewriting the last value set at this interval, for devices which otherwise revert to their defaults
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub refresh_interval: Option<Duration>,
+
+    #[serde(
         with = "humantime_serde",
         default = "default_register_interval",
         alias = "period",
@@ -276,7 +1108,15 @@ pub struct Register {
     pub interval: Duration,
 }
 
-fn default_register_interval() -> Duration {
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+pub(crate) fn default_register_interval() -> Duration {
     Duration::from_secs(60)
 }
 
@@ -287,16 +1127,19 @@ fn parse_empty_register_parser_defaults() {
     assert!(matches!(
         empty.unwrap(),
         RegisterParse {
-            swap_bytes: Swap(false),
-            swap_words: Swap(false),
+            byte_order,
             value_type: RegisterValueType::Numeric {
                 of: RegisterNumeric::U16,
                 adjust: RegisterNumericAdjustment {
                     scale: 0,
-                    offset: 0,
+                    scale_register: None,
+                    multiplier: None,
+                    divisor: None,
+                    offset,
+                    precision: None,
                 }
             }
-        }
+        } if byte_order.is_identity() && offset.is_zero()
     ));
 }
 
@@ -389,16 +1232,20 @@ fn parse_register_parser_scale_etc() {
             of: RegisterNumeric::I32,
             adjust: RegisterNumericAdjustment {
                 scale: -1,
-                offset: 20
+                offset,
+                ..
             }
-        }
+        } if offset == Decimal::from(20)
     ));
 }
 
 impl RegisterValueType {
     pub fn parse_words(&self, words: &[u16]) -> serde_json::Value {
-        use self::RegisterNumeric as N;
-        use rust_decimal::{prelude::FromPrimitive, Decimal, MathematicalOps};
+        self.parse_words_with_scale(words, None)
+    }
+
+    /// Parse words, using `scale` in place of any configured scale.
+    pub fn parse_words_with_scale(&self, words: &[u16], scale: Option<i8>) -> serde_json::Value {
         use serde_json::json;
         use RegisterValueType as T;
 
@@ -406,74 +1253,78 @@ impl RegisterValueType {
 
         match *self {
             T::Numeric { ref of, ref adjust } => {
-                let scale: Decimal = Decimal::TEN.powi(adjust.scale.into()).normalize();
-                let offset = Decimal::from(adjust.offset);
-                match of {
-                    N::U8 => json!(scale * Decimal::from(bytes[1]) + offset), // or is it 0?
-                    N::U16 => json!(scale * Decimal::from(words[0]) + offset),
-                    N::U32 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(u32::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::U64 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(u64::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::I8 => {
-                        json!(vec![bytes[1]]
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(i8::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::I16 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(i16::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::I32 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(i32::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::I64 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale * Decimal::from(i64::from_be_bytes(bytes)) + offset)
-                            .ok())
-                    }
-                    N::F32 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale
-                                * Decimal::from_f32(f32::from_be_bytes(bytes)).unwrap()
-                                + offset)
-                            .ok())
-                    }
-                    N::F64 => {
-                        json!(bytes
-                            .try_into()
-                            .map(|bytes| scale
-                                * Decimal::from_f64(f64::from_be_bytes(bytes)).unwrap()
-                                + offset)
-                            .ok())
-                    }
-                }
-            }
-            T::String(RegisterString { .. }) => {
-                json!(String::from_utf8_lossy(&bytes).trim_end_matches(char::from(0)))
+                let adjust = RegisterNumericAdjustment {
+                    scale: scale.unwrap_or(adjust.scale),
+                    ..adjust.clone()
+                };
+                json!(of.decode(&bytes).and_then(|v| adjust.apply(v)))
             }
+            T::String(ref string) => json!(string.decode(words)),
             T::Array(RegisterArray { .. }) => todo!(),
         }
     }
+
+    /// Encode a value into big-endian words, using `scale` in place of any configured scale. The inverse of
+    /// `parse_words_with_scale`.
+    pub fn encode_with_scale(
+        &self,
+        value: &serde_json::Value,
+        scale: Option<i8>,
+    ) -> crate::Result<Vec<Word>> {
+        use RegisterValueType as T;
+
+        match *self {
+            T::Numeric { ref of, ref adjust } => {
+                let number: Decimal = serde_json::from_value(value.clone())
+                    .map_err(|_| format!("expected a number, got {value}"))?;
+                let adjust = RegisterNumericAdjustment {
+                    scale: scale.unwrap_or(adjust.scale),
+                    ..adjust.clone()
+                };
+                let bytes = adjust
+                    .unapply(number)
+                    .and_then(|raw| of.encode(raw))
+                    .ok_or_else(|| format!("{number} is out of range for {}", of.type_name()))?;
+                Ok(bytes
+                    .chunks_exact(2)
+                    .map(|pair| Word::from_be_bytes([pair[0], pair[1]]))
+                    .collect())
+            }
+            T::String(ref string) => match value.as_str() {
+                Some(value) => string.encode(value),
+                None => Err(format!("expected a string, got {value}").into()),
+            },
+            T::Array(_) => Err("writing arrays is not supported".into()),
+        }
+    }
 }
 
 impl Register {
+    /// A register with every option other than where it is and how to parse it left at its default, as if configured
+    /// with just `address`, `register_type` and the `parse` options.
+    pub fn new(address: u16, register_type: RegisterType, parse: RegisterParse) -> Register {
+        Register {
+            name: None,
+            address,
+            register_type,
+            parse,
+            unit: None,
+            integrate: None,
+            aggregate: vec![],
+            payload_format: None,
+            publish_raw: true,
+            writable: false,
+            min: None,
+            max: None,
+            allowed_values: vec![],
+            flags: Default::default(),
+            write_function: None,
+            verify: false,
+            refresh_interval: None,
+            interval: default_register_interval(),
+        }
+    }
+
     pub fn size(&self) -> u8 {
         self.parse.value_type.size()
     }
@@ -487,23 +1338,61 @@ impl Register {
     }
 
     pub fn parse_words(&self, words: &[u16]) -> serde_json::Value {
-        self.parse.value_type.parse_words(&self.apply_swaps(words))
+        self.parse.parse_words(words)
     }
 
-    fn apply_swaps(&self, words: &[u16]) -> Vec<u16> {
-        let words: Vec<u16> = if self.parse.swap_bytes.0 {
-            words.iter().map(|v| v.swap_bytes()).collect()
-        } else {
-            words.into()
+    /// Parse words using a scale factor read from the register's `scale_register`.
+    ///
+    /// Scale factors outside of the range of `i8` (such as SunSpec's "not implemented" value of `0x8000`) produce a
+    /// `null` value.
+    pub fn parse_scaled_words(&self, words: &[u16], scale: i16) -> serde_json::Value {
+        match i8::try_from(scale) {
+            Ok(scale) => self
+                .parse
+                .value_type
+                .parse_words_with_scale(&self.parse.byte_order.apply_words(words), Some(scale)),
+            Err(_) => serde_json::Value::Null,
+        }
+    }
+
+    /// The AND and OR masks (as they appear on the wire) for a request to set a single flag, such as
+    /// `{"standby": true}`.
+    pub fn flag_masks(
+        &self,
+        request: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(Word, Word), String> {
+        if self.size() != 1 {
+            return Err("flags can only be set on single-register values".into());
+        }
+
+        let mut entries = request.iter();
+        let (Some((name, value)), None) = (entries.next(), entries.next()) else {
+            return Err("expected exactly one flag to set".into());
+        };
+        let bit = match self.flags.get(name) {
+            Some(&bit) if bit < 16 => bit,
+            Some(bit) => return Err(format!("bit {bit} of flag {name:?} is out of range")),
+            None => return Err(format!("unknown flag {name:?}")),
+        };
+        let Some(on) = value.as_bool() else {
+            return Err(format!(
+                "expected true or false for flag {name:?}, got {value}"
+            ));
         };
 
-        if self.parse.swap_words.0 {
-            words
-                .chunks_exact(2)
-                .flat_map(|chunk| vec![chunk[1], chunk[0]])
-                .collect()
-        } else {
-            words
+        let bit: Word = 1 << bit;
+        let masks = self
+            .parse
+            .byte_order
+            .unapply_words(&[!bit, if on { bit } else { 0 }]);
+        Ok((masks[0], masks[1]))
+    }
+
+    pub fn scale_register(&self) -> Option<u16> {
+        match self.parse.value_type {
+            RegisterValueType::Numeric { ref adjust, .. }
+            | RegisterValueType::Array(RegisterArray { ref adjust, .. }) => adjust.scale_register,
+            RegisterValueType::String(_) => None,
         }
     }
 }
@@ -514,23 +1403,17 @@ use pretty_assertions::assert_eq;
 fn test_parse_numeric() {
     use serde_json::json;
 
-    let reg = Register {
-        register_type: RegisterType::Input,
-        address: 42,
-        name: None,
-        interval: Default::default(),
-        parse: RegisterParse {
-            swap_bytes: Swap(false),
-            swap_words: Swap(true),
+    let reg = Register::new(
+        42,
+        RegisterType::Input,
+        RegisterParse {
+            byte_order: ByteOrder("CDAB".into()),
             value_type: RegisterValueType::Numeric {
                 of: RegisterNumeric::U32,
-                adjust: RegisterNumericAdjustment {
-                    scale: 0,
-                    offset: 0,
-                },
+                adjust: Default::default(),
             },
         },
-    };
+    );
 
     assert_eq!(reg.parse_words(&[843, 0]), json!(843));
 }
@@ -539,17 +1422,14 @@ fn test_parse_numeric() {
 fn test_parse_string() {
     use serde_json::json;
 
-    let reg = Register {
-        register_type: RegisterType::Input,
-        address: 42,
-        name: None,
-        interval: Default::default(),
-        parse: RegisterParse {
-            swap_bytes: Swap(false),
-            swap_words: Swap(false),
-            value_type: RegisterValueType::String(RegisterString { length: 10 }),
+    let reg = Register::new(
+        42,
+        RegisterType::Input,
+        RegisterParse {
+            byte_order: Default::default(),
+            value_type: RegisterValueType::String(RegisterString::new(10)),
         },
-    };
+    );
 
     assert_eq!(
         reg.parse_words(&[
@@ -558,3 +1438,494 @@ fn test_parse_string() {
         json!("hello world")
     );
 }
+
+#[test]
+fn parse_register_parser_byte_order() {
+    use serde_json::json;
+    let result = serde_json::from_value::<RegisterParse>(json!({
+        "type": "f32",
+        "byte_order": "badc",
+    }));
+    assert_eq!(result.unwrap().byte_order, ByteOrder("BADC".into()));
+
+    for invalid in ["ABC", "ABCC", "ABCE", ""] {
+        let result = serde_json::from_value::<RegisterParse>(json!({ "byte_order": invalid }));
+        assert!(result.is_err(), "{invalid:?} should be rejected");
+    }
+}
+
+#[test]
+fn parse_register_parser_legacy_swaps() {
+    use serde_json::json;
+    for (swap_bytes, swap_words, order) in [
+        (false, false, "ABCD"),
+        (true, false, "BADC"),
+        (false, true, "CDAB"),
+        (true, true, "DCBA"),
+    ] {
+        let result = serde_json::from_value::<RegisterParse>(json!({
+            "type": "u32",
+            "swap_bytes": swap_bytes,
+            "swap_words": swap_words,
+        }));
+        assert_eq!(result.unwrap().byte_order, ByteOrder(order.into()));
+    }
+}
+
+#[test]
+fn test_byte_order() {
+    let bytes = [1, 2, 3, 4, 5, 6, 7, 8];
+    let apply = |order: &str| ByteOrder::try_from(order.to_owned()).unwrap().apply(&bytes);
+
+    assert_eq!(apply("ABCD"), [1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(apply("CDAB"), [3, 4, 1, 2, 7, 8, 5, 6]);
+    assert_eq!(apply("BADC"), [2, 1, 4, 3, 6, 5, 8, 7]);
+    assert_eq!(apply("DCBA"), [4, 3, 2, 1, 8, 7, 6, 5]);
+    assert_eq!(apply("GHEFCDAB"), [7, 8, 5, 6, 3, 4, 1, 2]);
+    assert_eq!(apply("HGFEDCBA"), [8, 7, 6, 5, 4, 3, 2, 1]);
+
+    // Trailing words which don't fill the pattern keep their place, but have their bytes swapped as the pattern does
+    let apply = |order: &str, bytes: &[u8]| ByteOrder(order.into()).apply(bytes);
+    assert_eq!(apply("DCBA", &[1, 2, 3, 4, 5, 6]), [4, 3, 2, 1, 6, 5]);
+    assert_eq!(apply("BADC", &[1, 2, 3, 4, 5, 6]), [2, 1, 4, 3, 6, 5]);
+    assert_eq!(apply("CDAB", &[1, 2, 3, 4, 5, 6]), [3, 4, 1, 2, 5, 6]);
+    assert_eq!(apply("BADC", &[1, 2]), [2, 1]);
+    assert_eq!(apply("CDAB", &[1, 2]), [1, 2]);
+    assert_eq!(apply("GHEFCDAB", &[1, 2, 3, 4, 5, 6]), [1, 2, 3, 4, 5, 6]);
+    assert_eq!(apply("BADCFEHG", &[1, 2, 3, 4, 5, 6]), [2, 1, 4, 3, 6, 5]);
+
+    for order in [
+        "ABCD", "BADC", "CDAB", "DCBA", "GHEFCDAB", "HGFEDCBA", "BADCFEHG",
+    ] {
+        let order = ByteOrder(order.into());
+        for bytes in [
+            &[1, 2][..],
+            &[1, 2, 3, 4, 5, 6],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+        ] {
+            assert_eq!(order.unapply(&order.apply(bytes)), bytes, "{order:?}");
+        }
+    }
+}
+
+#[test]
+fn test_parse_legacy_swap_bytes() {
+    use serde_json::json;
+
+    let parse = |value_type: &str, words: &[u16]| {
+        serde_json::from_value::<RegisterParse>(json!({ "type": value_type, "swap_bytes": true }))
+            .unwrap()
+            .parse_words(words)
+    };
+
+    assert_eq!(parse("u16", &[0x3412]), json!(0x1234));
+    assert_eq!(
+        parse("u48", &[0x3412, 0x7856, 0xbc9a]),
+        json!(0x123456789abc_u64)
+    );
+}
+
+#[test]
+fn test_parse_extended_numerics() {
+    use serde_json::json;
+
+    let parse = |of: RegisterNumeric, words: &[u16]| {
+        RegisterValueType::Numeric {
+            of,
+            adjust: Default::default(),
+        }
+        .parse_words(words)
+    };
+
+    assert_eq!(parse(RegisterNumeric::Bcd16, &[0x1234]), json!(1234));
+    assert_eq!(
+        parse(RegisterNumeric::Bcd32, &[0x0012, 0x3456]),
+        json!(123456)
+    );
+    assert_eq!(parse(RegisterNumeric::Bcd16, &[0x12a4]), json!(null));
+    assert_eq!(
+        parse(RegisterNumeric::U48, &[0x0001, 0x0000, 0x0000]),
+        json!(4294967296u64)
+    );
+    assert_eq!(
+        parse(RegisterNumeric::I48, &[0xffff, 0xffff, 0xfffe]),
+        json!(-2)
+    );
+    assert_eq!(parse(RegisterNumeric::U48, &[0x0001, 0x0000]), json!(null));
+}
+
+#[test]
+fn test_parse_f64_with_byte_order() {
+    use serde_json::json;
+
+    let reg = Register::new(
+        42,
+        RegisterType::Holding,
+        RegisterParse {
+            byte_order: ByteOrder("BADCFEHG".into()),
+            value_type: RegisterValueType::Numeric {
+                of: RegisterNumeric::F64,
+                adjust: Default::default(),
+            },
+        },
+    );
+
+    // 1.5f64 is 0x3FF8_0000_0000_0000
+    assert_eq!(reg.parse_words(&[0xf83f, 0, 0, 0]), json!(1.5));
+}
+
+#[test]
+fn parse_register_parser_string_options() {
+    use serde_json::json;
+    let result = serde_json::from_value::<RegisterParse>(json!({
+        "type": "string",
+        "length": 4,
+        "encoding": "utf16le",
+        "byte_swap": true,
+        "trim": "whitespace",
+    }));
+
+    assert_eq!(
+        result.unwrap().value_type,
+        RegisterValueType::String(RegisterString {
+            length: 4,
+            encoding: StringEncoding::Utf16le,
+            byte_swap: true,
+            trim: StringTrim::Whitespace,
+        })
+    );
+}
+
+#[test]
+fn test_parse_string_encodings() {
+    let string = |encoding, byte_swap, trim| RegisterString {
+        length: 4,
+        encoding,
+        byte_swap,
+        trim,
+    };
+
+    // "AB  " with swapped bytes, space-padded
+    let words = [0x4241, 0x2020];
+    assert_eq!(
+        string(StringEncoding::Ascii, true, StringTrim::Whitespace).decode(&words),
+        "AB"
+    );
+    assert_eq!(
+        string(StringEncoding::Ascii, true, StringTrim::Nul).decode(&words),
+        "AB  "
+    );
+
+    assert_eq!(
+        string(StringEncoding::Latin1, false, StringTrim::Nul).decode(&[0x4ae9, 0x0000]),
+        "Jé"
+    );
+    assert_eq!(
+        string(StringEncoding::Ascii, false, StringTrim::Nul).decode(&[0x4ae9]),
+        "J\u{fffd}"
+    );
+    assert_eq!(
+        string(StringEncoding::Utf16be, false, StringTrim::Nul).decode(&[0x0048, 0x0069, 0x0000]),
+        "Hi"
+    );
+    assert_eq!(
+        string(StringEncoding::Utf16le, false, StringTrim::None).decode(&[0x4800, 0x6900]),
+        "Hi"
+    );
+}
+
+#[test]
+fn test_encode_string() {
+    let string = |encoding, byte_swap, trim| RegisterString {
+        length: 3,
+        encoding,
+        byte_swap,
+        trim,
+    };
+
+    let ascii = string(StringEncoding::Ascii, true, StringTrim::Whitespace);
+    assert_eq!(ascii.encode("ABC").unwrap(), [0x4241, 0x2043, 0x2020]);
+    assert_eq!(ascii.decode(&ascii.encode("ABC").unwrap()), "ABC");
+    assert!(ascii.encode("é").is_err());
+    assert!(ascii.encode("too long").is_err());
+
+    let utf16 = string(StringEncoding::Utf16le, false, StringTrim::Nul);
+    assert_eq!(utf16.encode("Hi").unwrap(), [0x4800, 0x6900, 0x0000]);
+    assert_eq!(utf16.decode(&utf16.encode("Hi").unwrap()), "Hi");
+
+    let latin1 = string(StringEncoding::Latin1, false, StringTrim::Nul);
+    assert_eq!(latin1.encode("é").unwrap(), [0xe900, 0x0000, 0x0000]);
+}
+
+#[test]
+fn parse_register_parser_fractional_adjustments() {
+    use serde_json::json;
+    let result = serde_json::from_value::<RegisterParse>(json!({
+        "type": "s16",
+        "multiplier": 0.0625,
+        "divisor": 3,
+        "offset": -40.5,
+        "precision": 2,
+    }));
+    assert_eq!(
+        result.unwrap().value_type,
+        RegisterValueType::Numeric {
+            of: RegisterNumeric::I16,
+            adjust: RegisterNumericAdjustment {
+                scale: 0,
+                scale_register: None,
+                multiplier: Some(Decimal::new(625, 4)),
+                divisor: Some(Decimal::from(3)),
+                offset: Decimal::new(-405, 1),
+                precision: Some(2),
+            }
+        }
+    );
+}
+
+#[test]
+fn test_parse_fractional_adjustments() {
+    use serde_json::json;
+
+    let parse = |adjust: serde_json::Value, words: &[u16]| {
+        serde_json::from_value::<RegisterValueType>(adjust)
+            .unwrap()
+            .parse_words(words)
+    };
+
+    // Temperature: value * 0.0625 - 40.5
+    assert_eq!(
+        parse(json!({"multiplier": 0.0625, "offset": -40.5}), &[1000]),
+        json!(22)
+    );
+    // Voltage: value * 0.01
+    assert_eq!(parse(json!({"multiplier": 0.01}), &[23012]), json!(230.12));
+    assert_eq!(
+        parse(json!({"divisor": 3, "precision": 2}), &[10]),
+        json!(3.33)
+    );
+    assert_eq!(
+        parse(json!({"scale": -1, "precision": 0}), &[125]),
+        json!(13)
+    );
+    assert_eq!(parse(json!({"divisor": 0}), &[10]), json!(null));
+    assert_eq!(parse(json!({"scale": 127}), &[10]), json!(null));
+    assert_eq!(parse(json!({"scale": -128}), &[10]), json!(null));
+}
+
+#[test]
+fn test_parse_scale_register() {
+    use serde_json::json;
+
+    let reg: Register = serde_json::from_value(json!({
+        "address": 40083,
+        "register_type": "holding",
+        "type": "s16",
+        "scale": 3,
+        "scale_register": 40084,
+    }))
+    .unwrap();
+
+    assert_eq!(reg.scale_register(), Some(40084));
+    assert_eq!(reg.parse_scaled_words(&[1234], -1), json!(123.4));
+    assert_eq!(reg.parse_scaled_words(&[1234], 2), json!(123400));
+    assert_eq!(reg.parse_scaled_words(&[1234], i16::MIN), json!(null));
+    // Scale factors beyond what a Decimal can hold give a bad reading rather than a panic
+    assert_eq!(reg.parse_scaled_words(&[1234], 29), json!(null));
+    assert_eq!(reg.parse_scaled_words(&[1234], -29), json!(null));
+    assert_eq!(reg.parse_words(&[1234]), json!(1234000));
+}
+
+#[test]
+fn parse_definitions() {
+    use serde_json::json;
+
+    let definition = serde_json::from_value::<Definition>(json!({
+        "name": "net_power",
+        "expression": "pv_power - export_power",
+    }));
+    assert!(matches!(definition, Ok(Definition::Computed(_))));
+
+    let definition = serde_json::from_value::<Definition>(json!({
+        "name": "pv_power",
+        "address": 5017,
+    }));
+    assert!(matches!(definition, Ok(Definition::Modbus(_))));
+
+    let definition = serde_json::from_value::<Definition>(json!({ "name": "nothing" }));
+    assert!(definition.is_err());
+}
+
+#[test]
+fn parse_register_aggregation() {
+    use serde_json::json;
+
+    let reg: Register = serde_json::from_value(json!({
+        "address": 5017,
+        "aggregate": ["1m", "15m"],
+        "publish_raw": false,
+    }))
+    .unwrap();
+
+    assert_eq!(
+        reg.aggregate,
+        [
+            Window(Duration::from_secs(60)),
+            Window(Duration::from_secs(900))
+        ]
+    );
+    assert!(!reg.publish_raw);
+
+    let reg: Register = serde_json::from_value(json!({ "address": 5017 })).unwrap();
+    assert!(reg.publish_raw);
+    assert!(serde_json::to_value(&reg)
+        .unwrap()
+        .get("publish_raw")
+        .is_none());
+}
+
+#[test]
+fn test_envelope_payload() {
+    use serde_json::json;
+
+    let reading = Ok((vec![0x034b, 0x0000], json!(843)));
+    assert_eq!(payload(PayloadFormat::Plain, &reading).unwrap(), "843");
+
+    let envelope: serde_json::Value =
+        serde_json::from_str(&payload(PayloadFormat::Envelope, &reading).unwrap()).unwrap();
+    assert_eq!(envelope["value"], json!(843));
+    assert_eq!(envelope["raw"], json!(["034b", "0000"]));
+    assert_eq!(envelope["quality"], json!("good"));
+    assert!(envelope["ts"].is_string());
+
+    let error = Err(crate::Error::Other("Modbus exception".into()));
+    assert_eq!(payload(PayloadFormat::Plain, &error), None);
+
+    let envelope: serde_json::Value =
+        serde_json::from_str(&payload(PayloadFormat::Envelope, &error).unwrap()).unwrap();
+    assert_eq!(envelope["value"], json!(null));
+    assert_eq!(envelope["quality"], json!("bad"));
+    assert_eq!(envelope["error"], json!("Modbus exception"));
+}
+
+#[test]
+fn test_encode_numeric() {
+    use serde_json::json;
+
+    let parse: RegisterParse = serde_json::from_value(json!({
+        "type": "s32",
+        "byte_order": "CDAB",
+        "scale": -1,
+        "offset": 10,
+    }))
+    .unwrap();
+
+    let words = parse.encode_with_scale(&json!(-12.3), None).unwrap();
+    assert_eq!(words, [0xff21, 0xffff]);
+    assert_eq!(parse.parse_words(&words), json!(-12.3));
+
+    // A scale read from the device takes the place of the configured one
+    let words = parse.encode_with_scale(&json!(110), Some(1)).unwrap();
+    assert_eq!(words, [10, 0]);
+    assert!(parse.encode_with_scale(&json!(110), Some(-29)).is_err());
+
+    let u16: RegisterParse = serde_json::from_value(json!({ "type": "u16" })).unwrap();
+    assert!(u16.encode_with_scale(&json!(70000), None).is_err());
+    assert!(u16.encode_with_scale(&json!(-1), None).is_err());
+    assert!(u16.encode_with_scale(&json!("ten"), None).is_err());
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    use serde_json::json;
+
+    for (parse, value) in [
+        (json!({ "type": "bcd16" }), json!(1234)),
+        (json!({ "type": "bcd32" }), json!(12345678)),
+        (json!({ "type": "i48" }), json!(-123456789)),
+        (
+            json!({ "type": "u48", "byte_order": "BCDA" }),
+            json!(0x1234_5678_9abc_u64),
+        ),
+        (json!({ "type": "f32" }), json!(1.5)),
+        (json!({ "type": "i8" }), json!(-5)),
+        (
+            json!({ "type": "u16", "multiplier": 0.5, "divisor": 4 }),
+            json!(2.5),
+        ),
+        (
+            json!({ "type": "string", "length": 4, "byte_order": "BA" }),
+            json!("hello"),
+        ),
+    ] {
+        let parse: RegisterParse = serde_json::from_value(parse).unwrap();
+        let words = parse.encode_with_scale(&value, None).unwrap();
+        assert_eq!(words.len(), parse.value_type.size() as usize);
+        assert_eq!(parse.parse_words(&words), value, "{parse:?}");
+    }
+}
+
+#[test]
+fn test_write_function() {
+    use serde_json::json;
+
+    let register: Register = serde_json::from_value(json!({
+        "address": 13049,
+        "write_function": "fc16",
+    }))
+    .unwrap();
+    assert_eq!(register.write_function, Some(WriteFunction::Multiple));
+
+    assert_eq!(WriteFunction::Auto.resolve(1), WriteFunction::Single);
+    assert_eq!(WriteFunction::Auto.resolve(2), WriteFunction::Multiple);
+    assert_eq!(WriteFunction::Multiple.resolve(1), WriteFunction::Multiple);
+    assert_eq!(
+        WriteFunction::ReadWrite.resolve(1),
+        WriteFunction::ReadWrite
+    );
+}
+
+#[test]
+fn test_flag_masks() {
+    use serde_json::json;
+
+    let flag = |value: serde_json::Value| value.as_object().unwrap().clone();
+
+    let mut register: Register = serde_json::from_value(json!({
+        "address": 13000,
+        "flags": { "standby": 0, "boost": 9, "broken": 16 },
+    }))
+    .unwrap();
+
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "boost": true }))),
+        Ok((0xfdff, 0x0200))
+    );
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "standby": false }))),
+        Ok((0xfffe, 0x0000))
+    );
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "standby": true, "boost": true }))),
+        Err("expected exactly one flag to set".into())
+    );
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "turbo": true }))),
+        Err("unknown flag \"turbo\"".into())
+    );
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "broken": true }))),
+        Err("bit 16 of flag \"broken\" is out of range".into())
+    );
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "boost": 1 }))),
+        Err("expected true or false for flag \"boost\", got 1".into())
+    );
+
+    // Masks are byte-swapped along with the value
+    register.parse.byte_order = ByteOrder("BA".into());
+    assert_eq!(
+        register.flag_masks(&flag(json!({ "boost": true }))),
+        Ok((0xfffd, 0x0002))
+    );
+}
@@ -0,0 +1,332 @@
+//! One-off reads and writes requested over MQTT, for commissioning devices without defining monitored registers.
+
+use super::{
+    register::{RegisterParse, RegisterType, RegisterValueType, WriteFunction},
+    Word,
+};
+use crate::mqtt::{self, Payload};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JSON;
+use tracing::{debug, warn};
+
+/// The topic under the connection to listen for requests on
+pub(crate) const REQUEST_TOPIC: &str = "rpc/request";
+
+/// The topic under the connection to publish responses under, each at `$RESPONSE_TOPIC/$id`
+const RESPONSE_TOPIC: &str = "rpc/response";
+
+/// Modbus limits reads to 125 registers
+const MAX_READ: u8 = 125;
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Request {
+    /// Identifies the request in the response topic. Must be a string or integer which is valid in a topic, and is
+    /// echoed back as-is.
+    #[serde(default)]
+    id: Option<JSON>,
+
+    #[serde(flatten)]
+    operation: Operation,
+
+    /// How to decode the words read or written into a value, using the same options as a register
+    #[serde(default)]
+    parse: Option<RegisterParse>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Read {
+        #[serde(default)]
+        register_type: RegisterType,
+        address: u16,
+        /// Defaults to the size of `parse`, or 1 if there is none
+        #[serde(default)]
+        count: Option<u8>,
+    },
+    Write {
+        #[serde(default = "holding")]
+        register_type: RegisterType,
+        address: u16,
+        values: Vec<Word>,
+        /// Defaults to the connection's `write_function`
+        #[serde(default)]
+        function: Option<WriteFunction>,
+    },
+}
+
+fn holding() -> RegisterType {
+    RegisterType::Holding
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<JSON>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    words: Option<Vec<Word>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JSON>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Request {
+    /// Check the request can be carried out, before sending anything to the device. Writes bypass the checks made on
+    /// writes to registers, so are only allowed if the connection opts in to them with `rpc_writes`.
+    fn validate(&self, writes_allowed: bool) -> crate::Result<()> {
+        if self.id.as_ref().is_some_and(|id| topic_id(id).is_none()) {
+            return Err("id must be a string or integer, without '/', '+' or '#'".into());
+        }
+
+        if let Some(RegisterParse {
+            value_type: RegisterValueType::Array(_),
+            ..
+        }) = self.parse
+        {
+            return Err("arrays can't be parsed in RPC requests".into());
+        }
+
+        if matches!(self.operation, Operation::Write { .. }) && !writes_allowed {
+            return Err(
+                "RPC writes are disabled for this connection, unless it sets `rpc_writes`".into(),
+            );
+        }
+
+        if let Operation::Read { count, .. } = self.operation {
+            let count = count.or_else(|| self.parse.as_ref().map(|parse| parse.value_type.size()));
+            if count.is_some_and(|count| count == 0 || count > MAX_READ) {
+                return Err(format!("count must be from 1 to {MAX_READ}").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn execute(self, modbus: &super::Handle) -> Response {
+        let words = match self.operation {
+            Operation::Read {
+                register_type,
+                address,
+                count,
+            } => {
+                let count = count
+                    .or_else(|| self.parse.as_ref().map(|parse| parse.value_type.size()))
+                    .unwrap_or(1);
+                match register_type {
+                    RegisterType::Input => modbus.read_input_register(address, count).await,
+                    RegisterType::Holding => modbus.read_holding_register(address, count).await,
+                }
+            }
+            Operation::Write {
+                register_type: RegisterType::Input,
+                ..
+            } => Err("input registers are read-only".into()),
+            Operation::Write {
+                address,
+                values,
+                function,
+                ..
+            } => modbus.write_with(function, address, values).await,
+        };
+
+        match words {
+            Ok(words) => Response {
+                id: self.id,
+                value: self.parse.map(|parse| parse.parse_words(&words)),
+                words: Some(words),
+                error: None,
+            },
+            Err(error) => Response {
+                id: self.id,
+                error: Some(error.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Execute a request, publishing the response to `rpc/response/$id` (or the request's MQTT v5 response topic).
+///
+/// `mqtt` is expected to be scoped to the connection. Writes are refused unless `writes_allowed`.
+pub(crate) async fn handle(
+    request: Payload,
+    mqtt: mqtt::Handle,
+    modbus: super::Handle,
+    writes_allowed: bool,
+) {
+    let response = match serde_json::from_slice::<Request>(&request.bytes) {
+        Ok(parsed) => {
+            debug!(?parsed, "RPC request");
+            match parsed.validate(writes_allowed) {
+                Ok(()) => parsed.execute(&modbus).await,
+                Err(error) => Response {
+                    id: parsed.id,
+                    error: Some(error.to_string()),
+                    ..Default::default()
+                },
+            }
+        }
+        Err(error) => Response {
+            // Echo the ID back even if the rest of the request is invalid, so that the caller isn't left waiting
+            id: serde_json::from_slice::<JSON>(&request.bytes)
+                .ok()
+                .and_then(|mut json| json.get_mut("id").map(JSON::take)),
+            error: Some(error.to_string()),
+            ..Default::default()
+        },
+    };
+
+    let payload = serde_json::to_string(&response).unwrap();
+    let mqtt = mqtt.with_content_type("application/json");
+    let sent = match mqtt.respond(&request.properties, payload.clone()).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            // Requests with an invalid ID are still answered, just without the ID in the topic
+            let topic = match response.id.as_ref().and_then(topic_id) {
+                None => RESPONSE_TOPIC.to_owned(),
+                Some(id) => format!("{RESPONSE_TOPIC}/{id}"),
+            };
+            mqtt.publish_under(topic, payload).await
+        }
+        Err(error) => Err(error),
+    };
+    if let Err(error) = sent {
+        warn!(?error, "unable to publish RPC response");
+    }
+}
+
+/// The topic level to publish the response to a request with `id` under, if `id` can be used as one.
+fn topic_id(id: &JSON) -> Option<String> {
+    match id {
+        JSON::String(id) if !id.is_empty() && !id.contains(['/', '+', '#', '\0']) => {
+            Some(id.clone())
+        }
+        JSON::Number(id) if id.is_i64() || id.is_u64() => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+#[test]
+fn parse_read_request() {
+    use super::register::{RegisterNumeric, RegisterValueType};
+    use serde_json::json;
+
+    let request: Request = serde_json::from_value(json!({
+        "id": 7,
+        "op": "read",
+        "register_type": "holding",
+        "address": 5000,
+        "parse": { "type": "u32", "byte_order": "CDAB" },
+    }))
+    .unwrap();
+
+    assert_eq!(request.id, Some(json!(7)));
+    assert_eq!(
+        request.operation,
+        Operation::Read {
+            register_type: RegisterType::Holding,
+            address: 5000,
+            count: None,
+        }
+    );
+    let parse = request.parse.unwrap();
+    assert!(matches!(
+        parse.value_type,
+        RegisterValueType::Numeric {
+            of: RegisterNumeric::U32,
+            ..
+        }
+    ));
+    assert_eq!(parse.parse_words(&[0x0001, 0x0002]), json!(0x0002_0001));
+}
+
+#[test]
+fn parse_write_request() {
+    use serde_json::json;
+
+    let request: Request = serde_json::from_value(json!({
+        "id": "abc",
+        "op": "write",
+        "address": 13049,
+        "values": [2],
+        "function": "fc16",
+    }))
+    .unwrap();
+
+    assert_eq!(
+        request.operation,
+        Operation::Write {
+            register_type: RegisterType::Holding,
+            address: 13049,
+            values: vec![2],
+            function: Some(WriteFunction::Multiple),
+        }
+    );
+    assert!(request.parse.is_none());
+}
+
+#[test]
+fn serialize_response() {
+    use serde_json::json;
+
+    let response = Response {
+        id: Some(json!("abc")),
+        words: Some(vec![0x0001, 0x0002]),
+        ..Default::default()
+    };
+    assert_eq!(
+        serde_json::to_value(&response).unwrap(),
+        json!({ "id": "abc", "words": [1, 2] })
+    );
+}
+
+#[test]
+fn validate_request() {
+    use serde_json::json;
+
+    let validate = |request: JSON| {
+        serde_json::from_value::<Request>(request)
+            .unwrap()
+            .validate(true)
+    };
+
+    assert!(validate(json!({ "id": "abc", "op": "read", "address": 1 })).is_ok());
+    assert!(validate(json!({ "id": 7, "op": "read", "address": 1, "count": 125 })).is_ok());
+    assert!(validate(json!({ "op": "write", "address": 1, "values": [1] })).is_ok());
+
+    // IDs which can't go in the response topic
+    for id in [
+        json!("a/b"),
+        json!("+"),
+        json!("#"),
+        json!(""),
+        json!(1.5),
+        json!([1]),
+        json!({}),
+    ] {
+        assert!(
+            validate(json!({ "id": id, "op": "read", "address": 1 })).is_err(),
+            "{id}"
+        );
+    }
+
+    assert!(validate(json!({ "op": "read", "address": 1, "count": 126 })).is_err());
+    assert!(validate(json!({ "op": "read", "address": 1, "count": 0 })).is_err());
+    assert!(validate(json!({
+        "op": "read",
+        "address": 1,
+        "parse": { "type": "array", "count": 4 },
+    }))
+    .is_err());
+
+    // Writes must be opted in to, while reads needn't be
+    let request =
+        |op| serde_json::from_value::<Request>(json!({ "op": op, "address": 1, "values": [1] }));
+    let error = request("write").unwrap().validate(false).unwrap_err();
+    assert!(error.to_string().contains("rpc_writes"), "{error}");
+    assert!(request("read").unwrap().validate(false).is_ok());
+}
@@ -0,0 +1,596 @@
+//! Writes to registers at scheduled times, such as forcing a battery to charge during off-peak hours.
+
+use super::register::IsDefault;
+use crate::mqtt;
+use chrono::{
+    DateTime, Datelike, FixedOffset, LocalResult, NaiveTime, TimeZone, Timelike, Utc, Weekday,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JSON;
+use std::time::Duration;
+
+/// The longest to sleep before checking the clock again, so that changes to the system clock are noticed
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// A value to write to a register whenever the entry is due.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(flatten)]
+    pub when: When,
+
+    // Name (or address, if unnamed) of the register on this connection to write to
+    pub register: String,
+
+    pub value: JSON,
+
+    // Of entries for the same register which are due at the same time, only the highest priority is written
+    #[serde(default, skip_serializing_if = "IsDefault::is_default")]
+    pub priority: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum When {
+    /// A cron expression of minute, hour, day of month, month and day of week, e.g. `"30 0 * * mon-fri"`
+    Cron { cron: Cron },
+
+    /// A time of day, e.g. `"00:30"`, optionally only on some days of the week
+    Daily {
+        at: TimeOfDay,
+
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        days: Vec<Weekday>,
+    },
+}
+
+impl When {
+    fn cron(&self) -> Cron {
+        match self {
+            When::Cron { cron } => cron.clone(),
+            When::Daily { at, days } => Cron::daily(*at, days),
+        }
+    }
+}
+
+/// A time of day with minute resolution, given as `"HH:MM"`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(NaiveTime);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(time: String) -> Result<Self, Self::Error> {
+        NaiveTime::parse_from_str(&time, "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| format!("invalid time {time:?}: expected \"HH:MM\""))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.0.format("%H:%M").to_string()
+    }
+}
+
+/// A parsed cron expression, holding the matching values of each field as a bit set.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+
+    // When both day fields are restricted, a day matching either is due (as in standard cron)
+    either_day: bool,
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    fn daily(at: TimeOfDay, days: &[Weekday]) -> Cron {
+        let weekdays = if days.is_empty() {
+            0x7f
+        } else {
+            days.iter()
+                .fold(0, |set, day| set | 1 << day.num_days_from_sunday())
+        };
+
+        Cron {
+            source: String::from(at),
+            minutes: 1 << at.0.minute(),
+            hours: 1 << at.0.hour(),
+            days: u32::MAX,
+            months: u16::MAX,
+            weekdays,
+            either_day: false,
+        }
+    }
+
+    fn is_due_on(&self, date: chrono::NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+
+        self.months & 1 << date.month() != 0
+            && if self.either_day {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+
+    /// The first time after `after` at which the expression is due, in the timezone `tz`.
+    ///
+    /// Local times skipped by a daylight saving transition are never due, and those repeated by one are only due the
+    /// first time around.
+    fn next_after<Tz: TimeZone>(&self, tz: &Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(tz).date_naive();
+
+        // Long enough to find the next 29th of February, at worst
+        for date in start.iter_days().take(366 * 8) {
+            if !self.is_due_on(date) {
+                continue;
+            }
+
+            for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                    let time = date.and_hms_opt(hour, minute, 0)?;
+                    let at = match tz.from_local_datetime(&time) {
+                        LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => at,
+                        LocalResult::None => continue,
+                    };
+                    let at = at.with_timezone(&Utc);
+                    if at > after {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let &[minutes, hours, days, months, weekdays] = &fields[..] else {
+            return Err(format!(
+                "invalid cron expression {source:?}: expected 5 fields (minute, hour, day of month, month and day of week)"
+            ));
+        };
+
+        let field = |spec: &str, min: u32, max: u32, names: &[&str]| {
+            parse_field(spec, min, max, names)
+                .map_err(|error| format!("invalid cron expression {source:?}: {error}"))
+        };
+
+        // Sunday may be given as either 0 or 7
+        let weekdays = field(weekdays, 0, 7, WEEKDAYS)?;
+        let weekdays = (weekdays | weekdays >> 7) as u8 & 0x7f;
+
+        Ok(Cron {
+            either_day: !days.starts_with('*') && !fields[4].starts_with('*'),
+            minutes: field(minutes, 0, 59, &[])?,
+            hours: field(hours, 0, 23, &[])? as u32,
+            days: field(days, 1, 31, &[])? as u32,
+            months: field(months, 1, 12, MONTHS)? as u16,
+            weekdays,
+            source,
+        })
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.source
+    }
+}
+
+/// Parse one field of a cron expression, such as `"*/15"` or `"mon-fri"`, into a bit set of the values it matches.
+/// `names`, if given, are alternatives to the numbers starting from `min`.
+fn parse_field(spec: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |value: &str| {
+        let position = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value));
+        match position {
+            Some(index) => Ok(min + index as u32),
+            None => value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{value:?} is not between {min} and {max}")),
+        }
+    };
+
+    let mut set = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("invalid step {step:?}")),
+            },
+            None => (part, None),
+        };
+
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (value(from)?, value(to)?),
+            // `5/10` means every 10th from 5, up to the maximum
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if from > to {
+            return Err(format!("range {range:?} is backwards"));
+        }
+
+        for value in (from..=to).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// The timezone which scheduled times are in: `"local"` (the default) for the system's timezone, `"utc"`, a fixed offset
+/// such as `"+10:00"`, or a named (IANA) timezone such as `"Australia/Melbourne"`. Local and named timezones follow
+/// their daylight saving rules.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Timezone {
+    #[default]
+    Local,
+    Fixed(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl Timezone {
+    fn next_after(&self, cron: &Cron, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Timezone::Local => cron.next_after(&chrono::Local, after),
+            Timezone::Fixed(offset) => cron.next_after(offset, after),
+            Timezone::Named(tz) => cron.next_after(tz, after),
+        }
+    }
+
+    fn localize(&self, at: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            Timezone::Local => at.with_timezone(&chrono::Local).fixed_offset(),
+            Timezone::Fixed(offset) => at.with_timezone(offset),
+            Timezone::Named(tz) => at.with_timezone(tz).fixed_offset(),
+        }
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    fn try_from(timezone: String) -> Result<Self, Self::Error> {
+        match timezone.to_ascii_lowercase().as_str() {
+            "local" => Ok(Timezone::Local),
+            "utc" | "z" => Ok(Timezone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            _ => timezone
+                .parse()
+                .map(Timezone::Fixed)
+                .or_else(|_| timezone.parse().map(Timezone::Named))
+                .map_err(|_| {
+                    format!("invalid timezone {timezone:?}: expected \"local\", \"utc\", an offset such as \"+10:00\" or a name such as \"Australia/Melbourne\"")
+                }),
+        }
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        match timezone {
+            Timezone::Local => "local".into(),
+            Timezone::Fixed(offset) => offset.to_string(),
+            Timezone::Named(tz) => tz.name().into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Schedule {
+    entries: Vec<Entry>,
+    timezone: Timezone,
+}
+
+/// The writes which are next due, as published to `schedule/next`
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Next {
+    pub at: DateTime<FixedOffset>,
+    pub actions: Vec<Entry>,
+}
+
+impl Next {
+    /// How long to sleep before checking whether the writes are due
+    pub fn wait(&self) -> Duration {
+        (self.at.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+            .min(MAX_WAIT)
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.at.with_timezone(&Utc) <= Utc::now()
+    }
+}
+
+impl Schedule {
+    pub fn new(entries: Vec<Entry>, timezone: Timezone) -> Schedule {
+        Schedule { entries, timezone }
+    }
+
+    /// Publish the writes which are next due to `schedule/next`, unless there are no entries at all.
+    pub async fn publish(&self, mqtt: &mqtt::Handle, next: Option<&Next>) -> crate::Result<()> {
+        if self.entries.is_empty() {
+            return Ok(());
+        }
+
+        mqtt.publish_retained_under("schedule/next", serde_json::to_vec(&next)?)
+            .await
+    }
+
+    /// The writes due at the first time after `after` that any entry is due.
+    ///
+    /// When several entries for the same register are due at once, only the one with the highest `priority` is
+    /// written, and of those with equal priority, the one listed last. The rest are written in the order listed.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<Next> {
+        let times: Vec<Option<DateTime<Utc>>> = self
+            .entries
+            .iter()
+            .map(|entry| self.timezone.next_after(&entry.when.cron(), after))
+            .collect();
+        let at = times.iter().flatten().min().copied()?;
+
+        let due: Vec<&Entry> = self
+            .entries
+            .iter()
+            .zip(&times)
+            .filter(|(_, time)| **time == Some(at))
+            .map(|(entry, _)| entry)
+            .collect();
+
+        let overrides = |(index, entry): &(usize, &&Entry)| {
+            let same = |other: &&&Entry| other.register == entry.register;
+            due[..*index]
+                .iter()
+                .filter(same)
+                .any(|other| other.priority > entry.priority)
+                || due[index + 1..]
+                    .iter()
+                    .filter(same)
+                    .any(|other| other.priority >= entry.priority)
+        };
+
+        Some(Next {
+            at: self.timezone.localize(at),
+            actions: due
+                .iter()
+                .enumerate()
+                .filter(|due| !overrides(due))
+                .map(|(_, entry)| (*entry).clone())
+                .collect(),
+        })
+    }
+}
+
+/// Publish to `schedule/error` why a scheduled write couldn't be made, as opposed to the register refusing it, which is
+/// published to its `set/result`.
+pub(crate) async fn publish_error(
+    mqtt: &mqtt::Handle,
+    action: &Entry,
+    error: &str,
+) -> crate::Result<()> {
+    let payload = serde_json::json!({ "action": action, "error": error });
+    mqtt.publish_under("schedule/error", serde_json::to_vec(&payload)?)
+        .await
+}
+
+#[cfg(test)]
+fn at(time: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(time).unwrap().into()
+}
+
+#[test]
+fn parse_schedule_entries() {
+    use serde_json::json;
+
+    let entries: Vec<Entry> = serde_json::from_value(json!([
+        {
+            "name": "force_charge",
+            "at": "00:30",
+            "days": ["mon", "Tuesday"],
+            "register": "forced_power",
+            "value": 5000,
+        },
+        {
+            "cron": "0 7 * * *",
+            "register": "forced_power",
+            "value": 0,
+            "priority": 1,
+        },
+    ]))
+    .unwrap();
+
+    assert_eq!(
+        entries[0].when,
+        When::Daily {
+            at: TimeOfDay(NaiveTime::from_hms_opt(0, 30, 0).unwrap()),
+            days: vec![Weekday::Mon, Weekday::Tue],
+        }
+    );
+    assert!(matches!(entries[1].when, When::Cron { ref cron } if cron.source == "0 7 * * *"));
+    assert_eq!(entries[1].priority, 1);
+
+    assert!(serde_json::from_value::<Entry>(json!({
+        "at": "25:00",
+        "register": "forced_power",
+        "value": 0,
+    }))
+    .is_err());
+}
+
+#[test]
+fn test_parse_cron() {
+    let cron = |source: &str| Cron::try_from(source.to_owned());
+
+    let every_quarter_hour = cron("*/15 9-17 * * mon-fri").unwrap();
+    assert_eq!(every_quarter_hour.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+    assert_eq!(every_quarter_hour.hours, 0b111111111 << 9);
+    assert_eq!(every_quarter_hour.weekdays, 0b0111110);
+    assert!(!every_quarter_hour.either_day);
+
+    let sundays = cron("0 0 1,15 jan,JUL 7").unwrap();
+    assert_eq!(sundays.days, 1 << 1 | 1 << 15);
+    assert_eq!(sundays.months, 1 << 1 | 1 << 7);
+    assert_eq!(sundays.weekdays, 1);
+    assert!(sundays.either_day);
+
+    assert_eq!(
+        cron("5/20 * * * *").unwrap().minutes,
+        1 << 5 | 1 << 25 | 1 << 45
+    );
+
+    assert!(cron("0 0 * *").is_err());
+    assert!(cron("60 * * * *").is_err());
+    assert!(cron("* * 0 * *").is_err());
+    assert!(cron("*/0 * * * *").is_err());
+    assert!(cron("5-1 * * * *").is_err());
+    assert!(cron("* * * foo *").is_err());
+}
+
+#[test]
+fn test_next_after() {
+    let utc = FixedOffset::east_opt(0).unwrap();
+    let brisbane = FixedOffset::east_opt(10 * 3600).unwrap();
+    let cron = |source: &str| Cron::try_from(source.to_owned()).unwrap();
+
+    // 2024-01-01 was a Monday
+    let now = at("2024-01-01T12:00:00Z");
+
+    assert_eq!(
+        cron("30 0 * * *").next_after(&utc, now),
+        Some(at("2024-01-02T00:30:00Z"))
+    );
+    assert_eq!(
+        cron("30 0 * * *").next_after(&brisbane, now),
+        Some(at("2024-01-02T00:30:00+10:00"))
+    );
+    assert_eq!(
+        cron("0 12 * * *").next_after(&utc, now),
+        Some(at("2024-01-02T12:00:00Z")),
+        "must be strictly after"
+    );
+    assert_eq!(
+        cron("0 9 * * sat").next_after(&utc, now),
+        Some(at("2024-01-06T09:00:00Z"))
+    );
+    assert_eq!(
+        cron("0 0 29 2 *").next_after(&utc, at("2024-03-01T00:00:00Z")),
+        Some(at("2028-02-29T00:00:00Z"))
+    );
+
+    // With both day fields restricted, either may match
+    assert_eq!(
+        cron("0 0 13 * fri").next_after(&utc, now),
+        Some(at("2024-01-05T00:00:00Z"))
+    );
+
+    assert_eq!(cron("0 0 31 2 *").next_after(&utc, now), None);
+}
+
+#[test]
+fn test_schedule_overlaps() {
+    use serde_json::json;
+
+    let entries: Vec<Entry> = serde_json::from_value(json!([
+        { "name": "a", "at": "00:30", "register": "forced_power", "value": 1000 },
+        { "name": "b", "at": "00:30", "register": "forced_power", "value": 2000 },
+        { "name": "c", "at": "00:30", "register": "mode", "value": 1 },
+        { "name": "d", "cron": "30 0 * * *", "register": "mode", "value": 2, "priority": 1 },
+        { "name": "e", "at": "00:30", "register": "mode", "value": 3 },
+        { "name": "f", "at": "07:00", "register": "forced_power", "value": 0 },
+    ]))
+    .unwrap();
+    let schedule = Schedule::new(entries, "+10:00".to_owned().try_into().unwrap());
+
+    let names = |next: &Next| -> Vec<String> {
+        next.actions
+            .iter()
+            .map(|action| action.name.clone().unwrap())
+            .collect()
+    };
+
+    let next = schedule.next_after(at("2024-01-01T12:00:00Z")).unwrap();
+    assert_eq!(next.at, at("2024-01-02T00:30:00+10:00"));
+    assert_eq!(next.at.offset().local_minus_utc(), 10 * 3600);
+    assert_eq!(names(&next), ["b", "d"]);
+
+    let next = schedule.next_after(next.at.into()).unwrap();
+    assert_eq!(next.at, at("2024-01-02T07:00:00+10:00"));
+    assert_eq!(names(&next), ["f"]);
+
+    assert!(Schedule::new(vec![], Timezone::Local)
+        .next_after(Utc::now())
+        .is_none());
+}
+
+#[test]
+fn parse_timezone() {
+    let timezone = |timezone: &str| Timezone::try_from(timezone.to_owned());
+
+    assert_eq!(timezone("Local"), Ok(Timezone::Local));
+    assert_eq!(
+        timezone("UTC"),
+        Ok(Timezone::Fixed(FixedOffset::east_opt(0).unwrap()))
+    );
+    assert_eq!(
+        timezone("-05:30"),
+        Ok(Timezone::Fixed(
+            FixedOffset::west_opt(5 * 3600 + 1800).unwrap()
+        ))
+    );
+    assert_eq!(
+        timezone("Australia/Brisbane"),
+        Ok(Timezone::Named(chrono_tz::Australia::Brisbane))
+    );
+    assert_eq!(
+        String::from(timezone("Australia/Brisbane").unwrap()),
+        "Australia/Brisbane"
+    );
+    assert!(timezone("Australia/Nowhere").is_err());
+}
+
+#[test]
+fn test_named_timezone() {
+    use serde_json::json;
+
+    let entries: Vec<Entry> = serde_json::from_value(json!([
+        { "at": "00:30", "register": "forced_power", "value": 1000 },
+    ]))
+    .unwrap();
+    let timezone = Timezone::try_from("Australia/Melbourne".to_owned()).unwrap();
+    let schedule = Schedule::new(entries, timezone);
+
+    // Daylight saving ends at 03:00 on 2024-04-07, so the offset changes between these writes
+    let next = schedule.next_after(at("2024-04-06T12:00:00Z")).unwrap();
+    assert_eq!(next.at, at("2024-04-07T00:30:00+11:00"));
+    assert_eq!(next.at.offset().local_minus_utc(), 11 * 3600);
+
+    let next = schedule.next_after(next.at.into()).unwrap();
+    assert_eq!(next.at, at("2024-04-08T00:30:00+10:00"));
+    assert_eq!(next.at.offset().local_minus_utc(), 10 * 3600);
+}
@@ -0,0 +1,325 @@
+//! A JSON Schema describing connection and register configs, for editors to validate and autocomplete them with.
+//!
+//! This is written by hand to follow the (rather flexible) shape that the configs are deserialized from, so it needs
+//! to be kept up to date with `connection::Config`, `connector::Config`, `register::Register` and
+//! `computed::Computed`.
+
+use serde_json::{json, Value as JSON};
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Schema for a connection config, as published to `$prefix/$id/connect`
+pub fn connection() -> JSON {
+    let mut schema = json!({
+        "$schema": DRAFT,
+        "title": "modbus-mqtt connection config",
+        "type": "object",
+        "properties": connection_properties(),
+        "required": ["proto"],
+        "allOf": [
+            {
+                "if": { "properties": { "proto": { "const": "tcp" } } },
+                "then": { "required": ["host"] },
+            },
+            {
+                "if": { "properties": { "proto": { "const": "rtu" } } },
+                "then": { "required": ["tty", "baud_rate"] },
+            },
+            {
+                "if": { "properties": { "proto": { "const": "winet-s" } } },
+                "then": { "required": ["host"] },
+            },
+        ],
+        "$defs": definitions(),
+    });
+    schema["$defs"]["schedule_entry"] = schedule_entry();
+    schema
+}
+
+/// Schema for a register config, as published to `$prefix/$id/registers/$name/config`
+pub fn register() -> JSON {
+    json!({
+        "$schema": DRAFT,
+        "title": "modbus-mqtt register config",
+        "$ref": "#/$defs/register",
+        "$defs": definitions(),
+    })
+}
+
+fn definitions() -> JSON {
+    json!({
+        "register": {
+            "anyOf": [
+                { "$ref": "#/$defs/computed_register" },
+                { "$ref": "#/$defs/modbus_register" },
+            ],
+        },
+        "computed_register": computed_register(),
+        "modbus_register": modbus_register(),
+        "duration": {
+            "type": "string",
+            "description": "A duration, e.g. \"30s\" or \"1m 30s\"",
+        },
+        "decimal": {
+            "type": ["number", "string"],
+        },
+    })
+}
+
+/// The fields of a connection config, including inline registers
+pub fn connection_properties() -> JSON {
+    let registers = json!({
+        "type": "array",
+        "items": { "$ref": "#/$defs/register" },
+        "deprecated": true,
+    });
+
+    json!({
+        "proto": {
+            "enum": ["tcp", "rtu", "winet-s"],
+            "description": "Protocol to connect with",
+        },
+        "host": { "type": "string", "description": "Host to connect to, for tcp and winet-s" },
+        "port": { "type": "integer", "minimum": 0, "maximum": 65535, "default": 502 },
+        "tty": { "type": "string", "description": "Serial device, for rtu" },
+        "baud_rate": { "type": "integer", "minimum": 1 },
+        "data_bits": { "enum": ["Five", "Six", "Seven", "Eight"], "default": "Eight" },
+        "stop_bits": { "enum": ["One", "Two"], "default": "One" },
+        "flow_control": { "enum": ["None", "Software", "Hardware"], "default": "None" },
+        "parity": { "enum": ["None", "Odd", "Even"], "default": "None" },
+        "unit": unit_id(),
+        "slave": unit_id(),
+        "address_offset": {
+            "type": "integer",
+            "minimum": -128,
+            "maximum": 127,
+            "default": 0,
+            "description": "Offset added to every register address",
+        },
+        "payload_format": payload_format(),
+        "read_only": { "type": "boolean", "default": false },
+        "write_function": write_function(),
+        "schedule": {
+            "type": "array",
+            "items": { "$ref": "#/$defs/schedule_entry" },
+        },
+        "timezone": {
+            "type": "string",
+            "default": "local",
+            "description": "\"local\", \"utc\" or a fixed offset such as \"+10:00\"",
+        },
+        "profile": { "enum": ["sunspec"] },
+        "input": registers,
+        "holding": registers,
+        "hold": registers,
+        "registers": registers,
+    })
+}
+
+fn computed_register() -> JSON {
+    json!({
+        "type": "object",
+        "properties": computed_properties(),
+        "required": ["name"],
+        "oneOf": [
+            { "required": ["expression"] },
+            { "required": ["expr"] },
+        ],
+    })
+}
+
+/// The fields of a computed register config
+pub fn computed_properties() -> JSON {
+    json!({
+        "name": { "type": "string" },
+        "expression": {
+            "type": "string",
+            "description": "Arithmetic over the values of other registers, e.g. \"pv1_power + pv2_power\"",
+        },
+        "expr": { "type": "string" },
+        "unit": { "type": "string" },
+        "precision": { "type": "integer", "minimum": 0, "maximum": 255 },
+    })
+}
+
+fn modbus_register() -> JSON {
+    json!({
+        "type": "object",
+        "properties": modbus_properties(),
+        "required": ["address"],
+        "allOf": [
+            {
+                "if": { "properties": { "type": { "const": "string" } }, "required": ["type"] },
+                "then": { "required": ["length"] },
+            },
+            {
+                "if": { "properties": { "type": { "const": "array" } }, "required": ["type"] },
+                "then": { "required": ["count"] },
+            },
+        ],
+    })
+}
+
+/// The names accepted for a register's `type`
+pub const TYPES: &[&str] = &[
+    "u8", "u16", "u32", "u48", "u64", "i8", "s8", "i16", "s16", "i32", "s32", "i48", "s48", "i64",
+    "s64", "f32", "f64", "bcd16", "bcd32", "array", "string",
+];
+
+/// The fields of a Modbus register config
+pub fn modbus_properties() -> JSON {
+    let numeric_types = &TYPES[..TYPES.len() - 2];
+
+    json!({
+        "name": { "type": "string" },
+        "address": { "type": "integer", "minimum": 0, "maximum": 65535 },
+        "register_type": { "enum": ["input", "holding"], "default": "input" },
+        "type": { "enum": TYPES, "default": "u16" },
+        "byte_order": {
+            "type": "string",
+            "pattern": "^(?i)([ab]{2}|[a-d]{4}|[a-h]{8})$",
+            "description": "Order of the value's bytes on the wire, e.g. \"CDAB\"",
+        },
+        "swap_bytes": { "type": "boolean", "deprecated": true },
+        "swap_words": { "type": "boolean", "deprecated": true },
+        "scale": {
+            "type": "integer",
+            "minimum": -128,
+            "maximum": 127,
+            "description": "Power of 10 to scale numeric values by",
+        },
+        "scale_register": { "type": "integer", "minimum": 0, "maximum": 65535 },
+        "multiplier": { "$ref": "#/$defs/decimal" },
+        "divisor": { "$ref": "#/$defs/decimal" },
+        "offset": { "$ref": "#/$defs/decimal" },
+        "precision": { "type": "integer", "minimum": 0, "maximum": 255 },
+        "count": {
+            "type": "integer",
+            "minimum": 1,
+            "maximum": 255,
+            "description": "Number of values in an array",
+        },
+        "of": { "enum": numeric_types, "default": "u16" },
+        "length": {
+            "type": "integer",
+            "minimum": 1,
+            "maximum": 255,
+            "description": "Length of a string, in registers",
+        },
+        "encoding": { "enum": ["ascii", "utf8", "utf16be", "utf16le", "latin1"], "default": "utf8" },
+        "byte_swap": { "type": "boolean", "default": false },
+        "trim": { "enum": ["none", "nul", "whitespace"], "default": "nul" },
+        "unit": { "type": "string" },
+        "integrate": {
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "reset": { "enum": ["daily"] },
+                "max_gap": { "$ref": "#/$defs/duration" },
+            },
+            "additionalProperties": false,
+        },
+        "aggregate": { "type": "array", "items": { "$ref": "#/$defs/duration" } },
+        "payload_format": payload_format(),
+        "publish_raw": { "type": "boolean", "default": true },
+        "writable": { "type": "boolean", "default": false },
+        "min": { "$ref": "#/$defs/decimal" },
+        "max": { "$ref": "#/$defs/decimal" },
+        "allowed_values": { "type": "array" },
+        "flags": {
+            "type": "object",
+            "additionalProperties": { "type": "integer", "minimum": 0, "maximum": 15 },
+            "description": "Names of the bits of a single-register value, which may be set on their own",
+        },
+        "write_function": write_function(),
+        "verify": { "type": "boolean", "default": false },
+        "refresh_interval": { "$ref": "#/$defs/duration" },
+        "interval": { "$ref": "#/$defs/duration", "default": "1m" },
+        "period": { "$ref": "#/$defs/duration" },
+        "duration": { "$ref": "#/$defs/duration" },
+    })
+}
+
+fn schedule_entry() -> JSON {
+    json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "cron": {
+                "type": "string",
+                "description": "Minute, hour, day of month, month and day of week, e.g. \"30 0 * * mon-fri\"",
+            },
+            "at": { "type": "string", "pattern": "^\\d{1,2}:\\d{2}$" },
+            "days": { "type": "array", "items": { "type": "string" } },
+            "register": { "type": "string" },
+            "value": {},
+            "priority": { "type": "integer", "default": 0 },
+        },
+        "required": ["register", "value"],
+        "oneOf": [
+            { "required": ["cron"] },
+            { "required": ["at"] },
+        ],
+    })
+}
+
+fn unit_id() -> JSON {
+    json!({ "type": "integer", "minimum": 0, "maximum": 255 })
+}
+
+fn payload_format() -> JSON {
+    json!({ "enum": ["plain", "envelope"], "default": "plain" })
+}
+
+fn write_function() -> JSON {
+    json!({
+        "enum": ["auto", "single", "fc06", "multiple", "fc16", "read_write", "fc23"],
+        "default": "auto",
+    })
+}
+
+#[test]
+fn test_schema_covers_registers() {
+    use super::register::Definition;
+
+    // Every field of a register, as it serializes
+    let registers = [
+        json!({
+            "name": "battery", "address": 5000, "register_type": "holding", "type": "s32", "byte_order": "CDAB",
+            "scale": -1, "scale_register": 5010, "multiplier": 2, "divisor": 3, "offset": 1, "precision": 2,
+            "unit": "W", "integrate": { "name": "battery_energy", "reset": "daily" }, "aggregate": ["1m"],
+            "payload_format": "envelope", "publish_raw": false, "writable": true, "min": 0, "max": 100,
+            "allowed_values": [1], "flags": { "standby": 0 }, "write_function": "fc16", "verify": true,
+            "refresh_interval": "30s", "interval": "10s",
+        }),
+        json!({
+            "address": 4989, "type": "string", "length": 10, "encoding": "ascii", "byte_swap": true,
+            "trim": "whitespace",
+        }),
+        json!({ "address": 4989, "type": "array", "count": 4, "of": "u32" }),
+        json!({ "name": "total", "expression": "a + b", "unit": "W", "precision": 1 }),
+    ];
+
+    for register in registers {
+        let definition: Definition = serde_json::from_value(register.clone()).unwrap();
+        let serialized = serde_json::to_value(definition).unwrap();
+        let properties = match serialized.get("expression") {
+            Some(_) => computed_properties(),
+            None => modbus_properties(),
+        };
+        for field in serialized.as_object().unwrap().keys() {
+            assert!(
+                properties.get(field).is_some(),
+                "{field} is not in the schema"
+            );
+        }
+    }
+
+    for register_type in TYPES {
+        let register = json!({ "address": 1, "type": register_type, "length": 1, "count": 1 });
+        assert!(
+            serde_json::from_value::<super::register::Register>(register).is_ok(),
+            "{register_type} is not a valid type"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Windowed statistics over a register's readings.
+
+use crate::mqtt::{self, Scopable};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// The length of an aggregation window, such as `"1m"`. Windows are aligned to the wall clock, so a `"1m"` window
+/// closes at the top of every minute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window(pub Duration);
+
+/// Units to name windows in, from the largest
+const UNITS: [(&str, u128); 5] = [
+    ("d", 86_400_000),
+    ("h", 3_600_000),
+    ("m", 60_000),
+    ("s", 1_000),
+    ("ms", 1),
+];
+
+impl Window {
+    /// The window's length in the largest unit it is a whole number of, such as `"90s"`, for use in topics.
+    fn name(&self) -> String {
+        let millis = self.0.as_millis();
+        let (unit, size) = UNITS
+            .into_iter()
+            .find(|&(_, size)| millis % size == 0)
+            .unwrap(); // every window is a whole number of milliseconds
+        format!("{}{unit}", millis / size)
+    }
+
+    /// How long after `now` until the window next closes
+    fn until_boundary(&self, now: SystemTime) -> Duration {
+        let window = self.0.as_millis();
+        let since_epoch = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Duration::from_millis((window - since_epoch % window) as u64)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(window: String) -> Result<Self, Self::Error> {
+        let duration = humantime_serde::re::humantime::parse_duration(&window)
+            .map_err(|error| format!("invalid window {window:?}: {error}"))?;
+
+        if duration.is_zero() || duration.subsec_nanos() % 1_000_000 != 0 {
+            return Err(format!(
+                "invalid window {window:?}: expected a non-zero whole number of milliseconds"
+            ));
+        }
+
+        Ok(Self(duration))
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        window.name()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct Stats {
+    avg: Decimal,
+    min: Decimal,
+    max: Decimal,
+    last: Decimal,
+    count: usize,
+
+    #[serde(skip)]
+    sum: Decimal,
+}
+
+impl Stats {
+    fn record(&mut self, value: Decimal) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.last = value;
+        self.sum += value;
+        self.count += 1;
+        self.avg = (self.sum / Decimal::from(self.count)).normalize();
+    }
+}
+
+/// Buffers readings for one window and publishes their statistics to `stats/$window` whenever the window closes.
+pub(crate) struct Aggregator {
+    mqtt: mqtt::Handle,
+    window: Window,
+    stats: Stats,
+    pub closes_at: Instant,
+}
+
+impl Aggregator {
+    /// `mqtt` is expected to be scoped to the register's topic.
+    pub fn new(window: Window, mqtt: &mqtt::Handle) -> Aggregator {
+        Aggregator {
+            mqtt: mqtt.scoped(format!("stats/{}", window.name())),
+            closes_at: Instant::now() + window.until_boundary(SystemTime::now()),
+            window,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn record(&mut self, value: Decimal) {
+        self.stats.record(value);
+    }
+
+    /// Publish and reset the statistics if the window has closed. Windows without readings publish nothing.
+    pub async fn close_if_due(&mut self, now: Instant) -> crate::Result<()> {
+        if now < self.closes_at {
+            return Ok(());
+        }
+
+        self.closes_at = now + self.window.until_boundary(SystemTime::now());
+        let stats = std::mem::take(&mut self.stats);
+        if stats.count > 0 {
+            self.mqtt.publish(serde_json::to_vec(&stats)?).await?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_window() {
+    let window: Window = serde_json::from_value(serde_json::json!("1m")).unwrap();
+    assert_eq!(window.name(), "1m");
+
+    // Names are compact, so that they can be used in topics
+    let name = |window: &str| Window::try_from(window.to_owned()).map(|window| window.name());
+    assert_eq!(name("1m 30s"), Ok("90s".into()));
+    assert_eq!(name("1h"), Ok("1h".into()));
+    assert_eq!(name("1day"), Ok("1d".into()));
+    assert_eq!(name("1500ms"), Ok("1500ms".into()));
+
+    assert!(name("0s").is_err());
+    assert!(name("1us").is_err());
+    assert!(name("soon").is_err());
+    assert_eq!(
+        serde_json::to_value(Window(Duration::from_secs(90))).unwrap(),
+        "90s"
+    );
+
+    let now = UNIX_EPOCH + Duration::from_secs(60 * 60 * 24 * 365 + 15);
+    assert_eq!(window.until_boundary(now), Duration::from_secs(45));
+    assert_eq!(
+        Window(Duration::from_secs(900)).until_boundary(now),
+        Duration::from_secs(885)
+    );
+}
+
+#[test]
+fn test_stats() {
+    use serde_json::json;
+
+    let mut stats = Stats::default();
+    for value in [3, 1, 4, 1, 5] {
+        stats.record(Decimal::from(value));
+    }
+
+    assert_eq!(
+        serde_json::to_value(&stats).unwrap(),
+        json!({ "avg": 2.8, "min": 1, "max": 5, "last": 5, "count": 5 })
+    );
+}
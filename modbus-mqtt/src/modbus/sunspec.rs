@@ -0,0 +1,515 @@
+//! Discovery of registers on SunSpec-compliant devices.
+//!
+//! SunSpec devices expose a `SunS` marker at one of a few well-known base addresses, followed by a chain of models,
+//! each prefixed by its ID and length (in registers). The chain ends with a model ID of `0xFFFF`. Each known model is
+//! mapped onto [`Register`] definitions, with scale factors wired up through `scale_register`.
+//!
+//! Only the most common models are described here. Unknown models are skipped.
+
+use super::register::{
+    Register, RegisterNumeric, RegisterNumericAdjustment, RegisterParse, RegisterString,
+    RegisterType, RegisterValueType,
+};
+use super::{connection, Word};
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+/// Addresses at which the `SunS` marker may be found
+const BASE_ADDRESSES: [u16; 3] = [0, 40000, 50000];
+
+/// "SunS", as two big-endian registers
+const MARKER: [Word; 2] = [0x5375, 0x6e53];
+
+const END_OF_CHAIN: u16 = 0xFFFF;
+
+/// Guards against walking forever on a device which never terminates its model chain
+const MAX_MODELS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Uint16,
+    Int16,
+    Acc32,
+    Float32,
+    Enum16,
+    Bitfield32,
+    ScaleFactor,
+    Str(u8),
+}
+
+#[derive(Debug)]
+struct Point {
+    name: &'static str,
+    offset: u16,
+    kind: Kind,
+    units: Option<&'static str>,
+    scale_factor: Option<&'static str>,
+}
+
+impl Point {
+    const fn new(name: &'static str, offset: u16, kind: Kind) -> Self {
+        Self {
+            name,
+            offset,
+            kind,
+            units: None,
+            scale_factor: None,
+        }
+    }
+
+    const fn units(mut self, units: &'static str) -> Self {
+        self.units = Some(units);
+        self
+    }
+
+    const fn sf(mut self, scale_factor: &'static str) -> Self {
+        self.scale_factor = Some(scale_factor);
+        self
+    }
+
+    fn value_type(&self) -> RegisterValueType {
+        use Kind::*;
+        let of = match self.kind {
+            Uint16 | Enum16 => RegisterNumeric::U16,
+            Int16 | ScaleFactor => RegisterNumeric::I16,
+            Acc32 | Bitfield32 => RegisterNumeric::U32,
+            Float32 => RegisterNumeric::F32,
+            Str(length) => return RegisterValueType::String(RegisterString::new(length)),
+        };
+        RegisterValueType::Numeric {
+            of,
+            adjust: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Model {
+    name: &'static str,
+    length: u16,
+    points: &'static [Point],
+}
+
+fn model(id: u16) -> Option<&'static Model> {
+    match id {
+        1 => Some(&COMMON),
+        101..=103 => Some(&INVERTER),
+        111..=113 => Some(&INVERTER_FLOAT),
+        201..=204 => Some(&METER),
+        _ => None,
+    }
+}
+
+/// Locate the SunSpec base address and generate register definitions for every known point in the model chain.
+pub(crate) async fn discover(modbus: &connection::Handle) -> crate::Result<Vec<Register>> {
+    let mut base = None;
+    for address in BASE_ADDRESSES {
+        if let Ok(words) = modbus.read_holding_register(address, 2).await {
+            if words == MARKER {
+                base = Some(address);
+                break;
+            }
+        }
+    }
+    let base = base.ok_or("SunSpec marker not found")?;
+    info!(base, "Found SunSpec marker");
+
+    let mut registers = vec![];
+    let mut seen: HashMap<&'static str, usize> = HashMap::new();
+    let mut address = base + MARKER.len() as u16;
+
+    for _ in 0..MAX_MODELS {
+        let header = modbus.read_holding_register(address, 2).await?;
+        let (id, length) = match header[..] {
+            [END_OF_CHAIN, _] => break,
+            [id, length] => (id, length),
+            _ => return Err("Invalid SunSpec model header".into()),
+        };
+
+        let points = address
+            .checked_add(2)
+            .ok_or("SunSpec model chain overflows address space")?;
+        match model(id) {
+            Some(model) => {
+                let count = seen.entry(model.name).or_default();
+                *count += 1;
+                debug!(id, length, address, name = model.name, "SunSpec model");
+                registers.extend(model.registers(points, length, *count));
+            }
+            None => debug!(id, length, address, "Skipping unknown SunSpec model"),
+        }
+
+        address = points
+            .checked_add(length)
+            .ok_or("SunSpec model chain overflows address space")?;
+    }
+
+    Ok(registers)
+}
+
+impl Model {
+    /// Registers for each (non scale factor) point of the model whose points begin at `address`. Points beyond the
+    /// `length` reported by the device are omitted. `instance` is used to distinguish names when the same model appears
+    /// more than once.
+    fn registers(&self, address: u16, length: u16, instance: usize) -> Vec<Register> {
+        let prefix = if instance > 1 {
+            format!("{}{}", self.name, instance)
+        } else {
+            self.name.to_owned()
+        };
+
+        self.points
+            .iter()
+            .filter(|point| point.kind != Kind::ScaleFactor)
+            .filter(|point| {
+                point.offset + point.value_type().size() as u16 <= length.min(self.length)
+            })
+            .filter_map(|point| {
+                // A device reporting a model near the end of the address space could put points beyond it
+                let last_word = point.offset + point.value_type().size() as u16 - 1;
+                let (Some(point_address), Some(_)) = (
+                    address.checked_add(point.offset),
+                    address.checked_add(last_word),
+                ) else {
+                    debug!(point = point.name, "SunSpec point overflows address space");
+                    return None;
+                };
+
+                let scale_factor = point
+                    .scale_factor
+                    .and_then(|sf| self.points.iter().find(|p| p.name == sf));
+                let scale_register = match scale_factor {
+                    Some(sf) => Some(address.checked_add(sf.offset)?),
+                    None => None,
+                };
+
+                let mut value_type = point.value_type();
+                if let RegisterValueType::Numeric { ref mut adjust, .. } = value_type {
+                    *adjust = RegisterNumericAdjustment {
+                        scale_register,
+                        ..Default::default()
+                    };
+                }
+
+                let parse = RegisterParse {
+                    value_type,
+                    ..Default::default()
+                };
+                Some(Register {
+                    name: Some(format!("{}_{}", prefix, point.name.to_lowercase())),
+                    unit: point.units.map(Into::into),
+                    ..Register::new(point_address, RegisterType::Holding, parse)
+                })
+            })
+            .collect()
+    }
+}
+
+use Kind::*;
+
+const COMMON: Model = Model {
+    name: "common",
+    length: 66,
+    points: &[
+        Point::new("Mn", 0, Str(16)),
+        Point::new("Md", 16, Str(16)),
+        Point::new("Opt", 32, Str(8)),
+        Point::new("Vr", 40, Str(8)),
+        Point::new("SN", 48, Str(16)),
+        Point::new("DA", 64, Uint16),
+    ],
+};
+
+// Models 101 (single phase), 102 (split phase) and 103 (three phase) share a layout
+const INVERTER: Model = Model {
+    name: "inverter",
+    length: 50,
+    points: &[
+        Point::new("A", 0, Uint16).units("A").sf("A_SF"),
+        Point::new("AphA", 1, Uint16).units("A").sf("A_SF"),
+        Point::new("AphB", 2, Uint16).units("A").sf("A_SF"),
+        Point::new("AphC", 3, Uint16).units("A").sf("A_SF"),
+        Point::new("A_SF", 4, ScaleFactor),
+        Point::new("PPVphAB", 5, Uint16).units("V").sf("V_SF"),
+        Point::new("PPVphBC", 6, Uint16).units("V").sf("V_SF"),
+        Point::new("PPVphCA", 7, Uint16).units("V").sf("V_SF"),
+        Point::new("PhVphA", 8, Uint16).units("V").sf("V_SF"),
+        Point::new("PhVphB", 9, Uint16).units("V").sf("V_SF"),
+        Point::new("PhVphC", 10, Uint16).units("V").sf("V_SF"),
+        Point::new("V_SF", 11, ScaleFactor),
+        Point::new("W", 12, Int16).units("W").sf("W_SF"),
+        Point::new("W_SF", 13, ScaleFactor),
+        Point::new("Hz", 14, Uint16).units("Hz").sf("Hz_SF"),
+        Point::new("Hz_SF", 15, ScaleFactor),
+        Point::new("VA", 16, Int16).units("VA").sf("VA_SF"),
+        Point::new("VA_SF", 17, ScaleFactor),
+        Point::new("VAr", 18, Int16).units("var").sf("VAr_SF"),
+        Point::new("VAr_SF", 19, ScaleFactor),
+        Point::new("PF", 20, Int16).units("Pct").sf("PF_SF"),
+        Point::new("PF_SF", 21, ScaleFactor),
+        Point::new("WH", 22, Acc32).units("Wh").sf("WH_SF"),
+        Point::new("WH_SF", 24, ScaleFactor),
+        Point::new("DCA", 25, Uint16).units("A").sf("DCA_SF"),
+        Point::new("DCA_SF", 26, ScaleFactor),
+        Point::new("DCV", 27, Uint16).units("V").sf("DCV_SF"),
+        Point::new("DCV_SF", 28, ScaleFactor),
+        Point::new("DCW", 29, Int16).units("W").sf("DCW_SF"),
+        Point::new("DCW_SF", 30, ScaleFactor),
+        Point::new("TmpCab", 31, Int16).units("C").sf("Tmp_SF"),
+        Point::new("TmpSnk", 32, Int16).units("C").sf("Tmp_SF"),
+        Point::new("TmpTrns", 33, Int16).units("C").sf("Tmp_SF"),
+        Point::new("TmpOt", 34, Int16).units("C").sf("Tmp_SF"),
+        Point::new("Tmp_SF", 35, ScaleFactor),
+        Point::new("St", 36, Enum16),
+        Point::new("StVnd", 37, Enum16),
+        Point::new("Evt1", 38, Bitfield32),
+        Point::new("Evt2", 40, Bitfield32),
+        Point::new("EvtVnd1", 42, Bitfield32),
+        Point::new("EvtVnd2", 44, Bitfield32),
+        Point::new("EvtVnd3", 46, Bitfield32),
+        Point::new("EvtVnd4", 48, Bitfield32),
+    ],
+};
+
+// Models 111 (single phase), 112 (split phase) and 113 (three phase) share a layout
+const INVERTER_FLOAT: Model = Model {
+    name: "inverter",
+    length: 60,
+    points: &[
+        Point::new("A", 0, Float32).units("A"),
+        Point::new("AphA", 2, Float32).units("A"),
+        Point::new("AphB", 4, Float32).units("A"),
+        Point::new("AphC", 6, Float32).units("A"),
+        Point::new("PPVphAB", 8, Float32).units("V"),
+        Point::new("PPVphBC", 10, Float32).units("V"),
+        Point::new("PPVphCA", 12, Float32).units("V"),
+        Point::new("PhVphA", 14, Float32).units("V"),
+        Point::new("PhVphB", 16, Float32).units("V"),
+        Point::new("PhVphC", 18, Float32).units("V"),
+        Point::new("W", 20, Float32).units("W"),
+        Point::new("Hz", 22, Float32).units("Hz"),
+        Point::new("VA", 24, Float32).units("VA"),
+        Point::new("VAr", 26, Float32).units("var"),
+        Point::new("PF", 28, Float32).units("Pct"),
+        Point::new("WH", 30, Float32).units("Wh"),
+        Point::new("DCA", 32, Float32).units("A"),
+        Point::new("DCV", 34, Float32).units("V"),
+        Point::new("DCW", 36, Float32).units("W"),
+        Point::new("TmpCab", 38, Float32).units("C"),
+        Point::new("TmpSnk", 40, Float32).units("C"),
+        Point::new("TmpTrns", 42, Float32).units("C"),
+        Point::new("TmpOt", 44, Float32).units("C"),
+        Point::new("St", 46, Enum16),
+        Point::new("StVnd", 47, Enum16),
+        Point::new("Evt1", 48, Bitfield32),
+        Point::new("Evt2", 50, Bitfield32),
+        Point::new("EvtVnd1", 52, Bitfield32),
+        Point::new("EvtVnd2", 54, Bitfield32),
+        Point::new("EvtVnd3", 56, Bitfield32),
+        Point::new("EvtVnd4", 58, Bitfield32),
+    ],
+};
+
+// Models 201 (single phase), 202 (split phase), 203 (wye) and 204 (delta) share a layout
+const METER: Model = Model {
+    name: "meter",
+    length: 105,
+    points: &[
+        Point::new("A", 0, Int16).units("A").sf("A_SF"),
+        Point::new("AphA", 1, Int16).units("A").sf("A_SF"),
+        Point::new("AphB", 2, Int16).units("A").sf("A_SF"),
+        Point::new("AphC", 3, Int16).units("A").sf("A_SF"),
+        Point::new("A_SF", 4, ScaleFactor),
+        Point::new("PhV", 5, Int16).units("V").sf("V_SF"),
+        Point::new("PhVphA", 6, Int16).units("V").sf("V_SF"),
+        Point::new("PhVphB", 7, Int16).units("V").sf("V_SF"),
+        Point::new("PhVphC", 8, Int16).units("V").sf("V_SF"),
+        Point::new("PPV", 9, Int16).units("V").sf("V_SF"),
+        Point::new("PPVphAB", 10, Int16).units("V").sf("V_SF"),
+        Point::new("PPVphBC", 11, Int16).units("V").sf("V_SF"),
+        Point::new("PPVphCA", 12, Int16).units("V").sf("V_SF"),
+        Point::new("V_SF", 13, ScaleFactor),
+        Point::new("Hz", 14, Int16).units("Hz").sf("Hz_SF"),
+        Point::new("Hz_SF", 15, ScaleFactor),
+        Point::new("W", 16, Int16).units("W").sf("W_SF"),
+        Point::new("WphA", 17, Int16).units("W").sf("W_SF"),
+        Point::new("WphB", 18, Int16).units("W").sf("W_SF"),
+        Point::new("WphC", 19, Int16).units("W").sf("W_SF"),
+        Point::new("W_SF", 20, ScaleFactor),
+        Point::new("VA", 21, Int16).units("VA").sf("VA_SF"),
+        Point::new("VAphA", 22, Int16).units("VA").sf("VA_SF"),
+        Point::new("VAphB", 23, Int16).units("VA").sf("VA_SF"),
+        Point::new("VAphC", 24, Int16).units("VA").sf("VA_SF"),
+        Point::new("VA_SF", 25, ScaleFactor),
+        Point::new("VAR", 26, Int16).units("var").sf("VAR_SF"),
+        Point::new("VARphA", 27, Int16).units("var").sf("VAR_SF"),
+        Point::new("VARphB", 28, Int16).units("var").sf("VAR_SF"),
+        Point::new("VARphC", 29, Int16).units("var").sf("VAR_SF"),
+        Point::new("VAR_SF", 30, ScaleFactor),
+        Point::new("PF", 31, Int16).units("Pct").sf("PF_SF"),
+        Point::new("PFphA", 32, Int16).units("Pct").sf("PF_SF"),
+        Point::new("PFphB", 33, Int16).units("Pct").sf("PF_SF"),
+        Point::new("PFphC", 34, Int16).units("Pct").sf("PF_SF"),
+        Point::new("PF_SF", 35, ScaleFactor),
+        Point::new("TotWhExp", 36, Acc32).units("Wh").sf("TotWh_SF"),
+        Point::new("TotWhExpPhA", 38, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWhExpPhB", 40, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWhExpPhC", 42, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWhImp", 44, Acc32).units("Wh").sf("TotWh_SF"),
+        Point::new("TotWhImpPhA", 46, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWhImpPhB", 48, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWhImpPhC", 50, Acc32)
+            .units("Wh")
+            .sf("TotWh_SF"),
+        Point::new("TotWh_SF", 52, ScaleFactor),
+        Point::new("TotVAhExp", 53, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhExpPhA", 55, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhExpPhB", 57, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhExpPhC", 59, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhImp", 61, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhImpPhA", 63, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhImpPhB", 65, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAhImpPhC", 67, Acc32)
+            .units("VAh")
+            .sf("TotVAh_SF"),
+        Point::new("TotVAh_SF", 69, ScaleFactor),
+        Point::new("TotVArhImpQ1", 70, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ1PhA", 72, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ1PhB", 74, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ1PhC", 76, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ2", 78, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ2PhA", 80, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ2PhB", 82, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhImpQ2PhC", 84, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ3", 86, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ3PhA", 88, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ3PhB", 90, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ3PhC", 92, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ4", 94, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ4PhA", 96, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ4PhB", 98, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArhExpQ4PhC", 100, Acc32)
+            .units("varh")
+            .sf("TotVArh_SF"),
+        Point::new("TotVArh_SF", 102, ScaleFactor),
+        Point::new("Evt", 103, Bitfield32),
+    ],
+};
+
+#[test]
+fn models_are_consistent() {
+    for model in [&COMMON, &INVERTER, &INVERTER_FLOAT, &METER] {
+        let mut next = 0;
+        for point in model.points {
+            assert!(
+                point.offset >= next,
+                "{}.{} overlaps",
+                model.name,
+                point.name
+            );
+            next = point.offset + point.value_type().size() as u16;
+
+            if let Some(sf) = point.scale_factor {
+                assert!(
+                    model
+                        .points
+                        .iter()
+                        .any(|p| p.name == sf && p.kind == Kind::ScaleFactor),
+                    "{}.{} references unknown scale factor {sf}",
+                    model.name,
+                    point.name
+                );
+            }
+        }
+        assert!(next <= model.length, "{} exceeds its length", model.name);
+    }
+}
+
+#[test]
+fn model_registers() {
+    use serde_json::json;
+
+    let registers = model(103).unwrap().registers(40071, 50, 2);
+    let watts = registers
+        .iter()
+        .find(|r| r.name.as_deref() == Some("inverter2_w"))
+        .unwrap();
+
+    assert_eq!(watts.address, 40083);
+    assert_eq!(watts.scale_register(), Some(40084));
+    assert_eq!(watts.unit.as_deref(), Some("W"));
+    assert_eq!(watts.parse_scaled_words(&[0xfffe], 1), json!(-20));
+
+    // Scale factors are applied, not published
+    assert!(registers
+        .iter()
+        .all(|r| !r.name.as_ref().unwrap().ends_with("_sf")));
+
+    let common = model(1).unwrap().registers(40002, 65, 1);
+    assert_eq!(common[0].name.as_deref(), Some("common_mn"));
+    assert_eq!(common[0].size(), 16);
+    assert_eq!(common.last().unwrap().name.as_deref(), Some("common_da"));
+
+    // Devices may implement a shorter version of a model
+    let short = model(1).unwrap().registers(40002, 48, 1);
+    assert_eq!(short.last().unwrap().name.as_deref(), Some("common_vr"));
+
+    // Points beyond the end of the address space are skipped
+    let end = model(103).unwrap().registers(65530, 50, 1);
+    assert!(end.iter().all(|r| r.address >= 65530));
+    assert!(end.len() < registers.len());
+}
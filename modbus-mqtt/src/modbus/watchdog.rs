@@ -0,0 +1,142 @@
+//! Periodic rewriting of a register's last commanded value, for devices which revert to their defaults unless a setpoint
+//! is kept alive (such as Sungrow inverters in external EMS mode).
+
+use super::write::{Outcome, Status as WriteStatus};
+use crate::mqtt::{self, Scopable};
+use serde::Serialize;
+use serde_json::Value as JSON;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Whether a command releases the register from the watchdog rather than being a value to write
+pub(crate) fn is_release(command: &[u8]) -> bool {
+    command.iter().all(u8::is_ascii_whitespace)
+        || serde_json::from_slice::<JSON>(command).is_ok_and(|value| value.is_null())
+}
+
+/// The state of a watchdog, published (retained) to `$register/watchdog`
+#[derive(Debug, Serialize)]
+struct Status<'a> {
+    active: bool,
+
+    value: &'a JSON,
+
+    #[serde(with = "humantime_serde")]
+    refresh_interval: Duration,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_write: Option<chrono::DateTime<chrono::Utc>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_status: Option<WriteStatus>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Keeps rewriting the last value commanded via `$register/set` every `refresh_interval`, until released with an empty
+/// or `null` command.
+pub(crate) struct Watchdog {
+    mqtt: mqtt::Handle,
+    refresh_interval: Duration,
+    command: Option<JSON>,
+    last_write: Option<chrono::DateTime<chrono::Utc>>,
+    last_status: Option<WriteStatus>,
+    error: Option<String>,
+    pub due_at: Option<Instant>,
+}
+
+impl Watchdog {
+    /// `mqtt` is expected to be scoped to the register's topic.
+    pub async fn new(refresh_interval: Duration, mqtt: &mqtt::Handle) -> Watchdog {
+        let watchdog = Watchdog {
+            mqtt: mqtt.scoped("watchdog"),
+            refresh_interval,
+            command: None,
+            last_write: None,
+            last_status: None,
+            error: None,
+            due_at: None,
+        };
+
+        // Replace any state retained from before a restart, as the commanded value didn't survive it
+        watchdog.publish().await;
+        watchdog
+    }
+
+    /// The value to rewrite, if one has been commanded
+    pub fn command(&self) -> Option<JSON> {
+        self.command.clone()
+    }
+
+    /// Record the outcome of a write, which starts the watchdog unless the value was rejected (in which case the
+    /// previously commanded value, if any, continues to be rewritten).
+    pub async fn record(&mut self, outcome: &Outcome) {
+        if outcome.status != WriteStatus::Rejected {
+            self.command = Some(outcome.value.clone());
+        }
+        if self.command.is_some() {
+            self.due_at = Some(Instant::now() + self.refresh_interval);
+        }
+
+        self.last_write = Some(chrono::Utc::now());
+        self.last_status = Some(outcome.status);
+        self.error = outcome.error.clone();
+        self.publish().await;
+    }
+
+    /// Stop rewriting the commanded value
+    pub async fn release(&mut self) {
+        self.command = None;
+        self.due_at = None;
+        self.publish().await;
+    }
+
+    async fn publish(&self) {
+        let status = Status {
+            active: self.command.is_some(),
+            value: self.command.as_ref().unwrap_or(&JSON::Null),
+            refresh_interval: self.refresh_interval,
+            last_write: self.last_write,
+            last_status: self.last_status,
+            error: self.error.as_deref(),
+        };
+
+        let published = match serde_json::to_vec(&status) {
+            Ok(payload) => self.mqtt.publish_retained(payload).await,
+            Err(error) => Err(error.into()),
+        };
+        if let Err(error) = published {
+            warn!(?error, "unable to publish watchdog status");
+        }
+    }
+}
+
+#[test]
+fn test_is_release() {
+    assert!(is_release(b""));
+    assert!(is_release(b" \n"));
+    assert!(is_release(b"null"));
+    assert!(!is_release(b"0"));
+    assert!(!is_release(b"\"\""));
+    assert!(!is_release(b"off"));
+}
+
+#[test]
+fn serialize_status() {
+    use serde_json::json;
+
+    let status = Status {
+        active: true,
+        value: &json!(5000),
+        refresh_interval: Duration::from_secs(30),
+        last_write: None,
+        last_status: Some(WriteStatus::Ok),
+        error: None,
+    };
+    assert_eq!(
+        serde_json::to_value(&status).unwrap(),
+        json!({ "active": true, "value": 5000, "refresh_interval": "30s", "last_status": "ok" })
+    );
+}
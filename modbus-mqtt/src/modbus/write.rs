@@ -0,0 +1,309 @@
+//! Writing values to registers, as requested by publishing to `$register/set`.
+
+use super::{
+    register::{Register, RegisterType},
+    Word,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::Value as JSON;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long to wait for the device to respond to each request involved in a write
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The value was written (and, if verifying, read back unchanged)
+    Ok,
+    /// The value was written without error, but a different value was read back
+    Mismatch,
+    /// The device responded with an error
+    Exception,
+    /// The device didn't respond in time
+    Timeout,
+    /// The value was not written, because it isn't valid for the register
+    Rejected,
+}
+
+/// The result of a write, published to `$register/set/result`
+#[derive(Debug, Serialize)]
+pub struct Outcome {
+    pub status: Status,
+
+    /// The value which was requested to be written
+    pub value: JSON,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub written: Option<Vec<Word>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_back: Option<Vec<Word>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+type Failure = (Status, String);
+
+/// Parse a requested value as JSON, accepting bare strings for string registers rather than insisting they are quoted
+pub(crate) fn parse_value(request: &[u8]) -> JSON {
+    serde_json::from_slice(request)
+        .unwrap_or_else(|_| JSON::String(String::from_utf8_lossy(request).into_owned()))
+}
+
+/// Encode `value` with the register's parse options and write it, reading it back afterwards if the register has
+/// `verify` set.
+pub(crate) async fn write(register: &Register, value: JSON, modbus: &super::Handle) -> Outcome {
+    write_with_timeout(register, value, modbus, TIMEOUT).await
+}
+
+/// As `write`, but waiting `timeout` (rather than the default of 10s) for each request involved.
+pub(crate) async fn write_with_timeout(
+    register: &Register,
+    value: JSON,
+    modbus: &super::Handle,
+    timeout: Duration,
+) -> Outcome {
+    let mut outcome = Outcome {
+        status: Status::Ok,
+        value,
+        written: None,
+        read_back: None,
+        error: None,
+    };
+
+    if let Err((status, error)) = attempt(register, modbus, timeout, &mut outcome).await {
+        outcome.status = status;
+        outcome.error = Some(error);
+    }
+
+    outcome
+}
+
+async fn attempt(
+    register: &Register,
+    modbus: &super::Handle,
+    timeout: Duration,
+    outcome: &mut Outcome,
+) -> Result<(), Failure> {
+    if let Some(request) = outcome
+        .value
+        .as_object()
+        .filter(|_| !register.flags.is_empty())
+    {
+        let masks = check_access(register, modbus.is_read_only())
+            .and_then(|_| register.flag_masks(request))
+            .map_err(|reason| (Status::Rejected, reason))?;
+        return set_flag(register, masks, modbus, timeout, outcome).await;
+    }
+
+    check(register, &outcome.value, modbus.is_read_only())
+        .map_err(|reason| (Status::Rejected, reason))?;
+
+    let scale = match register.scale_register() {
+        Some(address) => {
+            let words = call(timeout, modbus.read_holding_register(address, 1)).await?;
+            let scale = words.first().map(|&word| word as i16).unwrap_or_default();
+            Some(i8::try_from(scale).map_err(|_| {
+                (
+                    Status::Exception,
+                    format!("invalid scale factor {scale} read from {address}"),
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    let words = register
+        .parse
+        .encode_with_scale(&outcome.value, scale)
+        .map_err(|error| (Status::Rejected, error.to_string()))?;
+    outcome.written = Some(words.clone());
+
+    call(
+        timeout,
+        modbus.write_with(register.write_function, register.address, words.clone()),
+    )
+    .await?;
+
+    if register.verify {
+        let read_back = call(
+            timeout,
+            modbus.read_holding_register(register.address, register.size()),
+        )
+        .await?;
+        let matches = read_back == words;
+        outcome.read_back = Some(read_back);
+        if !matches {
+            return Err((
+                Status::Mismatch,
+                "the value read back differs from the value written".into(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Set or clear a single flag with a mask write, leaving the register's other bits as they are.
+async fn set_flag(
+    register: &Register,
+    (and_mask, or_mask): (Word, Word),
+    modbus: &super::Handle,
+    timeout: Duration,
+    outcome: &mut Outcome,
+) -> Result<(), Failure> {
+    call(
+        timeout,
+        modbus.mask_write_register(register.write_function, register.address, and_mask, or_mask),
+    )
+    .await?;
+
+    if register.verify {
+        let read_back = call(timeout, modbus.read_holding_register(register.address, 1)).await?;
+        let matches = read_back.first().map(|&word| word & !and_mask) == Some(or_mask);
+        outcome.read_back = Some(read_back);
+        if !matches {
+            return Err((
+                Status::Mismatch,
+                "the flag read back differs from the value written".into(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the register may be written at all, returning the reason if not.
+pub(super) fn check_access(register: &Register, read_only: bool) -> Result<(), String> {
+    if register.register_type == RegisterType::Input {
+        return Err("input registers are read-only".into());
+    }
+    if read_only {
+        return Err("connection is read-only".into());
+    }
+    if !register.writable {
+        return Err("register is not writable".into());
+    }
+    Ok(())
+}
+
+/// Check that the register may be written with `value`, returning the reason if not.
+fn check(register: &Register, value: &JSON, read_only: bool) -> Result<(), String> {
+    check_access(register, read_only)?;
+
+    let number = serde_json::from_value::<Decimal>(value.clone()).ok();
+
+    if !register.allowed_values.is_empty() {
+        let allowed = register.allowed_values.iter().any(|allowed| {
+            match (number, serde_json::from_value::<Decimal>(allowed.clone())) {
+                (Some(number), Ok(allowed)) => number == allowed,
+                _ => value == allowed,
+            }
+        });
+        if !allowed {
+            return Err(format!("{value} is not one of the allowed values"));
+        }
+    }
+
+    if let Some(number) = number {
+        if let Some(min) = register.min.filter(|&min| number < min) {
+            return Err(format!("{number} is less than the minimum of {min}"));
+        }
+        if let Some(max) = register.max.filter(|&max| number > max) {
+            return Err(format!("{number} is greater than the maximum of {max}"));
+        }
+    } else if register.min.is_some() || register.max.is_some() {
+        return Err(format!("expected a number, got {value}"));
+    }
+
+    Ok(())
+}
+
+async fn call<T>(
+    timeout: Duration,
+    request: impl Future<Output = crate::Result<T>>,
+) -> Result<T, Failure> {
+    match tokio::time::timeout(timeout, request).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(error)) => Err((Status::Exception, error.to_string())),
+        Err(_) => Err((Status::Timeout, "the device did not respond".into())),
+    }
+}
+
+#[test]
+fn serialize_outcome() {
+    use serde_json::json;
+
+    let outcome = Outcome {
+        status: Status::Mismatch,
+        value: json!(80),
+        written: Some(vec![80]),
+        read_back: Some(vec![100]),
+        error: Some("the value read back differs from the value written".into()),
+    };
+    assert_eq!(
+        serde_json::to_value(&outcome).unwrap(),
+        json!({
+            "status": "mismatch",
+            "value": 80,
+            "written": [80],
+            "read_back": [100],
+            "error": "the value read back differs from the value written",
+        })
+    );
+}
+
+#[test]
+fn test_check() {
+    use serde_json::json;
+
+    let mut register: Register = serde_json::from_value(json!({
+        "address": 13049,
+        "register_type": "holding",
+        "min": 0,
+        "max": 100,
+    }))
+    .unwrap();
+
+    assert_eq!(
+        check(&register, &json!(50), false),
+        Err("register is not writable".into())
+    );
+
+    register.writable = true;
+    assert_eq!(check(&register, &json!(50), false), Ok(()));
+    assert_eq!(check(&register, &json!(100.0), false), Ok(()));
+    assert_eq!(
+        check(&register, &json!(50), true),
+        Err("connection is read-only".into())
+    );
+    assert_eq!(
+        check(&register, &json!(-1), false),
+        Err("-1 is less than the minimum of 0".into())
+    );
+    assert_eq!(
+        check(&register, &json!(100.5), false),
+        Err("100.5 is greater than the maximum of 100".into())
+    );
+    assert_eq!(
+        check(&register, &json!("fifty"), false),
+        Err("expected a number, got \"fifty\"".into())
+    );
+
+    register.allowed_values = vec![json!(2), json!(3)];
+    assert_eq!(check(&register, &json!(2.0), false), Ok(()));
+    assert_eq!(
+        check(&register, &json!(4), false),
+        Err("4 is not one of the allowed values".into())
+    );
+
+    register.register_type = RegisterType::Input;
+    assert_eq!(
+        check(&register, &json!(2), false),
+        Err("input registers are read-only".into())
+    );
+}
@@ -0,0 +1,352 @@
+//! The MQTT protocol versions we speak, behind a common interface so that the rest of the crate doesn't need to care
+//! which one is in use.
+
+use super::{Payload, Properties, Publish, TlsOptions};
+use bytes::Bytes;
+use rumqttc::{v5, QoS};
+use std::time::Duration;
+use url::Url;
+
+/// Connection options for either protocol version. MQTT v5 is selected by suffixing the URL scheme with `5`, such as
+/// `mqtt5://` or `mqtts5://`.
+#[derive(Debug, Clone)]
+pub enum Options {
+    V4(Box<rumqttc::MqttOptions>),
+    V5(Box<v5::MqttOptions>),
+}
+
+impl TryFrom<Url> for Options {
+    type Error = crate::Error;
+
+    fn try_from(url: Url) -> crate::Result<Self> {
+        match without_v5_suffix(&url)? {
+            Some(url) => Ok(Options::V5(Box::new(url.try_into()?))),
+            None => Ok(Options::V4(Box::new(url.try_into()?))),
+        }
+    }
+}
+
+/// The URL with its scheme's MQTT v5 suffix removed (e.g. `ws5://` to `ws://`), if it has one.
+///
+/// `Url::set_scheme` refuses to change a scheme which the URL spec doesn't know of (like `ws5`) into one it does (like
+/// `ws`), so the URL is parsed afresh instead.
+fn without_v5_suffix(url: &Url) -> crate::Result<Option<Url>> {
+    let scheme @ ("mqtt5" | "mqtts5" | "ws5" | "wss5") = url.scheme() else {
+        return Ok(None);
+    };
+
+    let rest = &url.as_str()[scheme.len()..];
+    let url = Url::parse(&format!("{}{rest}", scheme.trim_end_matches('5')))
+        .map_err(|_| crate::Error::from("invalid MQTT URL"))?;
+    Ok(Some(url))
+}
+
+impl Options {
+    pub fn client_id(&self) -> String {
+        match self {
+            Options::V4(options) => options.client_id(),
+            Options::V5(options) => options.client_id(),
+        }
+    }
+
+    /// Configure the CA and client certificate of an encrypted (`mqtts://` or `wss://`) connection
+    #[cfg(feature = "rustls")]
+    pub fn set_tls(&mut self, tls: &TlsOptions) -> crate::Result<()> {
+        if tls.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            Options::V4(options) => {
+                let transport = super::tls::configure(options.transport(), tls)?;
+                options.set_transport(transport);
+            }
+            Options::V5(options) => {
+                let transport = super::tls::configure(options.transport(), tls)?;
+                options.set_transport(transport);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    pub fn set_tls(&mut self, tls: &TlsOptions) -> crate::Result<()> {
+        if tls.is_empty() {
+            Ok(())
+        } else {
+            Err("TLS options require modbus-mqtt to be built with the `tls` feature".into())
+        }
+    }
+
+    /// Set a retained message for the broker to publish if we disconnect unexpectedly
+    pub fn set_last_will<S: Into<String>, P: Into<Vec<u8>>>(&mut self, topic: S, payload: P) {
+        match self {
+            Options::V4(options) => {
+                options.set_last_will(rumqttc::LastWill::new(
+                    topic,
+                    payload,
+                    QoS::AtLeastOnce,
+                    true,
+                ));
+            }
+            Options::V5(options) => {
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    topic,
+                    payload,
+                    v5::mqttbytes::QoS::AtLeastOnce,
+                    true,
+                    None,
+                ));
+            }
+        }
+    }
+}
+
+/// The events from the broker which the connection acts upon
+pub(crate) enum Event {
+    Connected,
+    Publish(Payload),
+    Disconnected,
+    Other,
+}
+
+/// Why a publish wasn't handed to the event loop
+#[derive(Debug)]
+pub(crate) enum TryPublishError {
+    /// The event loop isn't accepting requests right now, so the publish should be tried again later
+    Busy,
+
+    /// The publish can never be sent
+    Invalid(&'static str),
+}
+
+impl From<rumqttc::ClientError> for TryPublishError {
+    fn from(_: rumqttc::ClientError) -> Self {
+        TryPublishError::Busy
+    }
+}
+
+impl From<v5::ClientError> for TryPublishError {
+    fn from(_: v5::ClientError) -> Self {
+        TryPublishError::Busy
+    }
+}
+
+pub(crate) enum Client {
+    V4 {
+        client: rumqttc::AsyncClient,
+        event_loop: Box<rumqttc::EventLoop>,
+    },
+    V5 {
+        client: v5::AsyncClient,
+        event_loop: Box<v5::EventLoop>,
+    },
+}
+
+impl Client {
+    pub fn new(options: Options, cap: usize) -> Client {
+        match options {
+            Options::V4(options) => {
+                let (client, event_loop) = rumqttc::AsyncClient::new(*options, cap);
+                Client::V4 {
+                    client,
+                    event_loop: Box::new(event_loop),
+                }
+            }
+            Options::V5(options) => {
+                let (client, event_loop) = v5::AsyncClient::new(*options, cap);
+                Client::V5 {
+                    client,
+                    event_loop: Box::new(event_loop),
+                }
+            }
+        }
+    }
+
+    pub async fn poll(&mut self) -> crate::Result<Event> {
+        use rumqttc::Outgoing;
+
+        Ok(match self {
+            Client::V4 { event_loop, .. } => {
+                use rumqttc::{Event as E, Incoming};
+
+                match event_loop.poll().await? {
+                    E::Incoming(Incoming::ConnAck(_)) => Event::Connected,
+                    E::Incoming(Incoming::Publish(publish)) => Event::Publish(Payload {
+                        topic: publish.topic,
+                        bytes: publish.payload,
+                        properties: Properties::default(),
+                    }),
+                    E::Outgoing(Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                }
+            }
+            Client::V5 { event_loop, .. } => {
+                use v5::{Event as E, Incoming};
+
+                match event_loop.poll().await? {
+                    E::Incoming(Incoming::ConnAck(_)) => Event::Connected,
+                    E::Incoming(Incoming::Publish(publish)) => Event::Publish(Payload {
+                        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                        bytes: publish.payload,
+                        properties: publish.properties.map(Into::into).unwrap_or_default(),
+                    }),
+                    E::Outgoing(Outgoing::Disconnect) => Event::Disconnected,
+                    _ => Event::Other,
+                }
+            }
+        })
+    }
+
+    /// Hand a publish to the event loop without waiting. MQTT v4 has no properties, so they are dropped.
+    pub fn try_publish(&self, publish: &Publish) -> Result<(), TryPublishError> {
+        // The client rejects invalid topics with the same error as when its channel is full, so check them first
+        if !rumqttc::valid_topic(&publish.topic) {
+            return Err(TryPublishError::Invalid("invalid topic"));
+        }
+
+        match self {
+            Client::V4 { client, .. } => client.try_publish(
+                publish.topic.clone(),
+                publish.qos,
+                publish.retain,
+                publish.payload.to_vec(),
+            )?,
+            Client::V5 { client, .. } => client.try_publish_with_properties(
+                publish.topic.clone(),
+                qos_v5(publish.qos),
+                publish.retain,
+                publish.payload.clone(),
+                publish.properties.clone().into(),
+            )?,
+        }
+        Ok(())
+    }
+
+    pub fn try_subscribe_many<I: IntoIterator<Item = String>>(
+        &self,
+        filters: I,
+    ) -> crate::Result<()> {
+        match self {
+            Client::V4 { client, .. } => client.try_subscribe_many(
+                filters
+                    .into_iter()
+                    .map(|path| rumqttc::SubscribeFilter::new(path, QoS::AtLeastOnce)),
+            )?,
+            Client::V5 { client, .. } => {
+                client.try_subscribe_many(filters.into_iter().map(|path| {
+                    v5::mqttbytes::v5::Filter::new(path, v5::mqttbytes::QoS::AtLeastOnce)
+                }))?
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_unsubscribe(&self, filter: String) -> crate::Result<()> {
+        match self {
+            Client::V4 { client, .. } => client.try_unsubscribe(filter)?,
+            Client::V5 { client, .. } => client.try_unsubscribe(filter)?,
+        }
+        Ok(())
+    }
+
+    pub fn try_disconnect(&self) -> crate::Result<()> {
+        match self {
+            Client::V4 { client, .. } => client.try_disconnect()?,
+            Client::V5 { client, .. } => client.try_disconnect()?,
+        }
+        Ok(())
+    }
+}
+
+fn qos_v5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+impl From<Properties> for v5::mqttbytes::v5::PublishProperties {
+    fn from(properties: Properties) -> Self {
+        Self {
+            content_type: properties.content_type,
+            message_expiry_interval: properties
+                .message_expiry
+                .map(|expiry| expiry.as_secs().clamp(1, u32::MAX as u64) as u32),
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data.map(Bytes::from),
+            user_properties: properties.user_properties,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<v5::mqttbytes::v5::PublishProperties> for Properties {
+    fn from(properties: v5::mqttbytes::v5::PublishProperties) -> Self {
+        Self {
+            content_type: properties.content_type,
+            message_expiry: properties
+                .message_expiry_interval
+                .map(|secs| Duration::from_secs(secs as u64)),
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data.map(|data| data.to_vec()),
+            user_properties: properties.user_properties,
+        }
+    }
+}
+
+#[test]
+fn parse_options() {
+    let v4 = Options::try_from(Url::parse("mqtt://localhost/?client_id=test").unwrap()).unwrap();
+    assert!(matches!(v4, Options::V4(_)));
+
+    let v5 = Options::try_from(Url::parse("mqtt5://localhost/?client_id=test").unwrap()).unwrap();
+    let Options::V5(options) = v5 else {
+        panic!("expected MQTT v5 options");
+    };
+    assert_eq!(options.broker_address(), ("localhost".to_owned(), 1883));
+}
+
+#[test]
+fn parse_v5_schemes() {
+    for (scheme, expected) in [
+        ("mqtt5", "mqtt"),
+        ("mqtts5", "mqtts"),
+        ("ws5", "ws"),
+        ("wss5", "wss"),
+    ] {
+        let url = Url::parse(&format!(
+            "{scheme}://user:pass@broker:1234/mqtt?client_id=test"
+        ))
+        .unwrap();
+        let url = without_v5_suffix(&url).unwrap().unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("{expected}://user:pass@broker:1234/mqtt?client_id=test")
+        );
+    }
+
+    let url = Url::parse("mqtt://broker/?client_id=test").unwrap();
+    assert!(without_v5_suffix(&url).unwrap().is_none());
+}
+
+#[cfg(feature = "ws")]
+#[test]
+fn parse_v5_websocket_options() {
+    let url = Url::parse("ws5://localhost:8000/mqtt?client_id=test").unwrap();
+    assert!(matches!(Options::try_from(url), Ok(Options::V5(_))));
+}
+
+#[test]
+fn tls_options_require_encrypted_transport() {
+    let mut options =
+        Options::try_from(Url::parse("mqtt://localhost/?client_id=test").unwrap()).unwrap();
+    assert!(options.set_tls(&TlsOptions::default()).is_ok());
+
+    let tls = TlsOptions {
+        ca_file: Some("ca.pem".into()),
+        ..Default::default()
+    };
+    assert!(options.set_tls(&tls).is_err());
+}
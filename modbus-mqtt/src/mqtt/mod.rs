@@ -0,0 +1,1056 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::Bytes;
+use rumqttc::{mqttbytes::matches as matches_topic, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{self, channel, Receiver, Sender},
+        watch,
+    },
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+mod client;
+mod spill;
+#[cfg(feature = "rustls")]
+mod tls;
+pub use client::Options;
+use client::{Client, Event, TryPublishError};
+use spill::Spill;
+
+/// How long to wait before reconnecting after the connection to the broker fails. The delay doubles with each
+/// consecutive failure, up to `MAX_RECONNECT_DELAY`.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait for outstanding publishes to be sent when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many spilled publishes to read from disk at a time when sending them
+const SPILL_BATCH: usize = 32;
+
+/// How often to publish the buffer's fill level while it's changing. It becoming empty is published straight away.
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Payload {
+    pub bytes: Bytes,
+    pub topic: String,
+    pub properties: Properties,
+}
+
+/// MQTT v5 message properties. These are ignored when connected with MQTT v4.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Properties {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+
+    /// How long the broker should hold on to the message for subscribers before discarding it
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub message_expiry: Option<Duration>,
+
+    /// The topic the receiver of a message should publish its response to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+
+    /// Data identifying a request, to be sent back in its response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_data: Option<Vec<u8>>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: Properties,
+}
+
+impl Publish {
+    fn new<S: Into<String>, B: Into<Bytes>>(topic: S, payload: B) -> Publish {
+        Publish {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            properties: Properties::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Subscribe(String, Sender<Payload>),
+    /// Drop the closed channels subscribed to a filter, unsubscribing from it if none are left
+    Unsubscribe(String),
+    Publish(Publish),
+    Shutdown,
+}
+
+/// Certificates for encrypted connections. Without a CA file, the system's trusted certificates are used.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    fn is_empty(&self) -> bool {
+        self.ca_file.is_none() && self.client_cert.is_none() && self.client_key.is_none()
+    }
+}
+
+/// Options for buffering outgoing publishes while the broker is unreachable.
+#[derive(Debug, Clone)]
+pub struct BufferOptions {
+    /// Maximum number of publishes to hold in memory. Once full, the oldest are spilled to `file`, or dropped without
+    /// one. The newest publish is always held, so a capacity of 0 behaves as 1.
+    pub capacity: usize,
+
+    /// File to spill publishes to when the buffer is full, and to save unsent publishes to on shutdown. Publishes in
+    /// the file are older than those in memory, so they are sent first, including after a restart.
+    pub file: Option<PathBuf>,
+
+    /// Maximum number of publishes to keep in `file`. Once full, the oldest are dropped.
+    pub file_capacity: usize,
+
+    /// Topic to publish the buffer's fill level to whenever the connection is (re-)established, and as it changes.
+    pub status_topic: Option<String>,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            file: None,
+            file_capacity: 100_000,
+            status_topic: None,
+        }
+    }
+}
+
+pub(crate) async fn new(options: Options, buffer: BufferOptions) -> Connection {
+    let client = Client::new(options, 32);
+
+    let (tx, rx) = channel(32);
+    Connection {
+        online: watch::channel(false).0,
+        client,
+        subscriptions: HashMap::new(),
+        buffer: Buffer::restore(buffer),
+        birth: None,
+        connected: false,
+        reconnect_delay: MIN_RECONNECT_DELAY,
+        reconnect_at: None,
+        tx,
+        rx,
+    }
+}
+
+// Maintain internal subscriptions as well as MQTT subscriptions. Relay all received messages on MQTT subscribed topics
+// to internal components who have a matching topic. Unsubscribe topics when no one is listening anymore.
+//
+// Outgoing publishes are queued in a buffer and only handed to the MQTT client while connected, so that readings taken
+// while the broker is unreachable are replayed, in order, once it is reachable again.
+pub(crate) struct Connection {
+    subscriptions: HashMap<String, Vec<Sender<Payload>>>,
+    buffer: Buffer,
+    birth: Option<Publish>,
+    connected: bool,
+    /// Tells handles whether `connected`
+    online: watch::Sender<bool>,
+    reconnect_delay: Duration,
+    reconnect_at: Option<Instant>,
+    tx: Sender<Message>,
+    rx: Receiver<Message>,
+    client: Client,
+}
+
+impl Connection {
+    pub async fn run(&mut self) -> crate::Result<()> {
+        loop {
+            select! {
+                event = self.client.poll(), if self.reconnect_at.is_none() => {
+                    match event {
+                        Ok(event) => self.handle_event(event).await?,
+                        Err(error) => {
+                            if self.connected {
+                                warn!(?error, "MQTT connection lost");
+                            } else {
+                                debug!(?error, "MQTT connection failed");
+                            }
+                            self.set_connected(false);
+                            self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
+                            self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
+                }
+                _ = sleep_until(self.reconnect_at.unwrap_or_else(Instant::now)), if self.reconnect_at.is_some() => {
+                    self.reconnect_at = None;
+                }
+                request = self.rx.recv() => {
+                    match request {
+                        None => break,
+                        Some(Message::Shutdown) => {
+                            info!("MQTT connection shutting down");
+                            self.disconnect().await;
+                            break;
+                        }
+                        Some(req) => self.handle_request(req).await?,
+                    }
+                }
+            }
+
+            self.flush();
+        }
+
+        self.buffer.save();
+        Ok(())
+    }
+
+    /// Set a retained message to publish every time the connection is (re-)established, ahead of anything buffered.
+    /// This is the counterpart to the last will, which the broker publishes when the connection is lost.
+    pub fn set_birth<S: Into<String>, B: Into<Bytes>>(&mut self, topic: S, payload: B) {
+        let mut publish = Publish::new(topic, payload);
+        publish.retain = true;
+        self.birth = Some(publish);
+    }
+
+    /// Send whatever is buffered and disconnect cleanly, giving up after `SHUTDOWN_TIMEOUT`.
+    async fn disconnect(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        self.flush();
+        if self.client.try_disconnect().is_err() {
+            return;
+        }
+
+        let drain = async {
+            loop {
+                match self.client.poll().await {
+                    Ok(Event::Disconnected) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, drain).await.is_err() {
+            warn!("timed out waiting for MQTT disconnect");
+        }
+        self.set_connected(false);
+    }
+
+    fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        self.online.send_replace(connected);
+    }
+
+    /// Hand as many buffered publishes to the MQTT client as it will take without blocking, starting with any spilled
+    /// to disk.
+    fn flush(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        self.send_status();
+        if self.buffer.send_spilled(&self.client) {
+            send(&self.client, &mut self.buffer.queue);
+        }
+        self.send_status();
+    }
+
+    /// Publish the buffer's fill level, if it's due. The status is sent around the buffer rather than through it, so
+    /// that it reflects the buffer as it is rather than as it was when queued.
+    fn send_status(&mut self) {
+        let now = Instant::now();
+        if let Some((status, publish)) = self.buffer.status(now) {
+            if try_send(&self.client, &publish) {
+                self.buffer.published_status = Some((status, now));
+            }
+        }
+    }
+
+    pub fn handle(&self, prefix: String) -> Handle {
+        Handle {
+            prefix,
+            tx: self.tx.clone(),
+            online: self.online.subscribe(),
+            properties: Properties::default(),
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) -> crate::Result<()> {
+        match event {
+            Event::Publish(payload) => {
+                debug!(topic = %payload.topic, bytes = ?payload.bytes, "publish");
+                self.handle_data(payload).await?;
+            }
+            Event::Connected => {
+                info!(buffered = self.buffer.queue.len(), "MQTT connected");
+                self.set_connected(true);
+                self.reconnect_delay = MIN_RECONNECT_DELAY;
+
+                // The broker may have lost our session, so subscribe to everything afresh
+                let filters: Vec<_> = self.subscriptions.keys().cloned().collect();
+                if !filters.is_empty() {
+                    if let Err(error) = self.client.try_subscribe_many(filters) {
+                        error!(?error, "unable to resubscribe");
+                    }
+                }
+
+                if let Some(birth) = self.birth.clone() {
+                    self.buffer.queue.push_front(birth);
+                }
+                // Publish how much built up while disconnected, ahead of sending it
+                self.buffer.published_status = None;
+            }
+            Event::Disconnected | Event::Other => {}
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self), fields(subscriptions = ?self.subscriptions.keys()))]
+    async fn handle_data(&mut self, payload: Payload) -> crate::Result<()> {
+        let Payload {
+            topic,
+            bytes,
+            properties,
+        } = payload;
+        let mut targets = vec![];
+        let mut unsubscribed = vec![];
+
+        // Remove subscriptions whose channels are closed, adding matching channels to the `targets` vec.
+        self.subscriptions.retain(|filter, channels| {
+            if matches_topic(&topic, filter) {
+                channels.retain(|channel| {
+                    if channel.is_closed() {
+                        warn!(?channel, "closed");
+                        false
+                    } else {
+                        targets.push(channel.clone());
+                        true
+                    }
+                });
+                if channels.is_empty() {
+                    unsubscribed.push(filter.clone());
+                }
+                !channels.is_empty()
+            } else {
+                true
+            }
+        });
+        for filter in unsubscribed {
+            self.unsubscribe(filter);
+        }
+
+        for target in targets {
+            if target
+                .send(Payload {
+                    topic: topic.clone(),
+                    bytes: bytes.clone(),
+                    properties: properties.clone(),
+                })
+                .await
+                .is_err()
+            {
+                // These will be removed above next time a matching payload is removed
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_request(&mut self, request: Message) -> crate::Result<()> {
+        debug!(?request);
+        match request {
+            Message::Publish(publish) => self.buffer.push(publish),
+            Message::Subscribe(filter, channel) => {
+                // NOTE: Curently allows multiple components to watch the same topic filter, but if there is no need
+                // for this, it might make more sense to have it _replace_ the channel, so that old (stale)
+                // components automatically finish running.
+                match self.subscriptions.get_mut(&filter) {
+                    Some(channels) => channels.push(channel),
+                    None => {
+                        self.subscriptions.insert(filter.clone(), vec![channel]);
+                    }
+                }
+
+                // While disconnected, this is subscribed to once the connection is re-established
+                if self.connected {
+                    self.client.try_subscribe_many([filter])?
+                }
+            }
+            Message::Unsubscribe(filter) => {
+                if let Some(channels) = self.subscriptions.get_mut(&filter) {
+                    channels.retain(|channel| !channel.is_closed());
+                    if channels.is_empty() {
+                        self.subscriptions.remove(&filter);
+                        self.unsubscribe(filter);
+                    }
+                }
+            }
+            Message::Shutdown => panic!("Handled by the caller"),
+        }
+        Ok(())
+    }
+
+    /// Stop the broker sending publishes for a filter which no one is subscribed to anymore. While disconnected there
+    /// is nothing to do, as it won't be resubscribed to.
+    fn unsubscribe(&self, filter: String) {
+        if self.connected {
+            if let Err(error) = self.client.try_unsubscribe(filter) {
+                warn!(?error, "unable to unsubscribe");
+            }
+        }
+    }
+}
+
+/// Hand publishes from the front of `queue` to the MQTT client until it stops accepting them, returning whether the
+/// queue was emptied.
+fn send(client: &Client, queue: &mut VecDeque<Publish>) -> bool {
+    while let Some(publish) = queue.front() {
+        if !try_send(client, publish) {
+            return false;
+        }
+        queue.pop_front();
+    }
+    true
+}
+
+/// Hand a publish to the MQTT client, returning whether it was taken off our hands: publishes which can never be sent
+/// are dropped, but those which the client is too busy for are not.
+fn try_send(client: &Client, publish: &Publish) -> bool {
+    match client.try_publish(publish) {
+        Ok(()) => true,
+        Err(TryPublishError::Busy) => false,
+        Err(TryPublishError::Invalid(error)) => {
+            error!(%error, topic = publish.topic, "dropping publish which can't be sent");
+            true
+        }
+    }
+}
+
+/// A bounded queue of outgoing publishes which spills the oldest publish to disk (or drops it) when full.
+struct Buffer {
+    queue: VecDeque<Publish>,
+    options: BufferOptions,
+    dropped: usize,
+
+    /// Publishes in the buffer's file, all of which are older than those in `queue`
+    spill: Option<Spill>,
+
+    /// The fill level last published to the status topic, and when
+    published_status: Option<(Status, Instant)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+struct Status {
+    buffered: usize,
+    capacity: usize,
+    dropped: usize,
+}
+
+/// A buffered publish, as saved to disk. Payloads are saved as text where they are valid UTF-8, and in base64
+/// otherwise.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    topic: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_base64: Option<String>,
+    qos: u8,
+    retain: bool,
+    #[serde(default, flatten)]
+    properties: Properties,
+}
+
+impl Buffer {
+    /// Create a buffer, picking up any publishes spilled or saved to its file by a previous run.
+    fn restore(options: BufferOptions) -> Buffer {
+        let spill = options
+            .file
+            .clone()
+            .map(|file| Spill::open(file, options.file_capacity));
+        if let Some(count) = spill.as_ref().map(Spill::len).filter(|&count| count > 0) {
+            info!(count, file = ?options.file, "restored buffered MQTT publishes");
+        }
+
+        Buffer {
+            queue: VecDeque::new(),
+            options,
+            dropped: 0,
+            spill,
+            published_status: None,
+        }
+    }
+
+    fn push(&mut self, publish: Publish) {
+        if self.queue.len() >= self.options.capacity {
+            if let Some(oldest) = self.queue.pop_front() {
+                if self.spill.is_some() {
+                    self.spill([oldest]);
+                } else {
+                    self.dropped += 1;
+                    warn!(
+                        dropped = self.dropped,
+                        "MQTT buffer full, dropping oldest publish"
+                    );
+                }
+            }
+        }
+        self.queue.push_back(publish);
+    }
+
+    /// Append publishes to the buffer's file, behind any already spilled. If they can't be written, they are dropped,
+    /// as are the oldest in the file once it's full.
+    fn spill(&mut self, publishes: impl IntoIterator<Item = Publish>) {
+        let lines: Vec<String> = publishes
+            .into_iter()
+            .filter_map(|publish| serde_json::to_string(&Saved::from(publish)).ok())
+            .collect();
+        let Some(ref mut spill) = self.spill else {
+            self.dropped += lines.len();
+            return;
+        };
+        if lines.is_empty() {
+            return;
+        }
+
+        let file = &self.options.file;
+        match spill.append(&lines) {
+            Ok(0) => debug!(count = lines.len(), ?file, "spilled MQTT publishes to disk"),
+            Ok(overflow) => {
+                self.dropped += overflow;
+                warn!(
+                    dropped = self.dropped,
+                    ?file,
+                    "MQTT buffer file full, dropping oldest publishes"
+                );
+            }
+            Err(error) => {
+                self.dropped += lines.len();
+                error!(
+                    ?error,
+                    ?file,
+                    "unable to spill MQTT publishes to disk, dropping them"
+                );
+            }
+        }
+    }
+
+    /// Hand as many spilled publishes to the MQTT client as it will take without blocking, oldest first, returning
+    /// whether none are left.
+    fn send_spilled(&mut self, client: &Client) -> bool {
+        let Some(ref mut spill) = self.spill else {
+            return true;
+        };
+
+        while spill.len() > 0 {
+            let sent = spill.read(SPILL_BATCH).and_then(|lines| {
+                if lines.is_empty() {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                let mut sent = 0;
+                for line in &lines {
+                    match read_saved(line) {
+                        Some(publish) if !try_send(client, &publish) => break,
+                        Some(_) => {}
+                        None => error!("dropping buffered publish which can't be read"),
+                    }
+                    sent += 1;
+                }
+                spill.consume(&lines[..sent])?;
+                Ok(sent == lines.len())
+            });
+
+            match sent {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(error) => {
+                    self.dropped += spill.len();
+                    error!(
+                        ?error,
+                        file = ?self.options.file,
+                        "unable to read spilled MQTT publishes, dropping them"
+                    );
+                    let _ = spill.clear();
+                }
+            }
+        }
+        true
+    }
+
+    /// A publish of the buffer's fill level to its status topic, if it has changed since it was last published and
+    /// `STATUS_INTERVAL` has passed, or it has become empty.
+    fn status(&self, now: Instant) -> Option<(Status, Publish)> {
+        let topic = self.options.status_topic.as_ref()?;
+        let status = Status {
+            buffered: self.queue.len() + self.spill.as_ref().map_or(0, Spill::len),
+            capacity: self.options.capacity,
+            dropped: self.dropped,
+        };
+
+        if let Some((published, at)) = self.published_status {
+            let due = status.buffered == 0 || now >= at + STATUS_INTERVAL;
+            if published == status || !due {
+                return None;
+            }
+        }
+
+        let json = serde_json::to_string(&status).ok()?;
+        let mut publish = Publish::new(topic, json);
+        publish.properties.content_type = Some("application/json".into());
+        Some((status, publish))
+    }
+
+    /// The publishes in the buffer's file, oldest first, leaving them there.
+    #[cfg(test)]
+    fn spilled(&self) -> Vec<Publish> {
+        let Some(ref spill) = self.spill else {
+            return vec![];
+        };
+        let lines = spill.read(usize::MAX).unwrap();
+        lines.iter().filter_map(|line| read_saved(line)).collect()
+    }
+
+    /// Save unsent publishes to the buffer's file, if it has one.
+    fn save(&mut self) {
+        let count = self.queue.len();
+        if self.spill.is_none() || count == 0 {
+            return;
+        }
+
+        let queue = std::mem::take(&mut self.queue);
+        self.spill(queue);
+        info!(count, file = ?self.options.file, "saved buffered MQTT publishes");
+    }
+}
+
+/// Parse a line of the buffer's file back into a publish.
+fn read_saved(line: &[u8]) -> Option<Publish> {
+    let saved = serde_json::from_slice::<Saved>(line).ok()?;
+    Publish::try_from(saved).ok()
+}
+
+impl From<Publish> for Saved {
+    fn from(publish: Publish) -> Self {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+
+        let (payload, payload_base64) = match std::str::from_utf8(&publish.payload) {
+            Ok(text) => (Some(text.to_owned()), None),
+            Err(_) => (None, Some(BASE64_STANDARD.encode(&publish.payload))),
+        };
+        Saved {
+            topic: publish.topic,
+            payload,
+            payload_base64,
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            properties: publish.properties,
+        }
+    }
+}
+
+impl TryFrom<Saved> for Publish {
+    type Error = base64::DecodeError;
+
+    fn try_from(saved: Saved) -> Result<Self, Self::Error> {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+
+        let payload = match (saved.payload, saved.payload_base64) {
+            (_, Some(encoded)) => BASE64_STANDARD.decode(encoded)?.into(),
+            (text, None) => text.unwrap_or_default().into(),
+        };
+        Ok(Publish {
+            topic: saved.topic,
+            payload,
+            qos: rumqttc::qos(saved.qos).unwrap_or(QoS::AtLeastOnce),
+            retain: saved.retain,
+            properties: saved.properties,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Handle {
+    prefix: String,
+    tx: Sender<Message>,
+    online: watch::Receiver<bool>,
+
+    /// Properties attached to everything published through this handle and the handles scoped from it
+    properties: Properties,
+}
+
+// IDEA: make subscribe+publish _generic_ over the payload type, as long as it implements a Payload trait we define,
+// which allows them to perform the serialization/deserialization to Bytes. For most domain types, the trait would be
+// implemented to use serde_json but for Bytes and Vec<u8> it would just return itself.
+// The return values may need to be crate::Result<Receiver<Option<T>> or crate::Result<Receiver<crate::Result<T>>>.
+impl Handle {
+    pub async fn subscribe(&self) -> crate::Result<Receiver<Payload>> {
+        let (tx_bytes, rx) = mpsc::channel(8);
+
+        let msg = Message::Subscribe(self.prefix.clone(), tx_bytes);
+        self.tx
+            .send(msg)
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        Ok(rx)
+    }
+
+    /// Unsubscribe from our topic, once the receivers returned by `subscribe` have been dropped.
+    pub async fn unsubscribe(&self) -> crate::Result<()> {
+        self.tx
+            .send(Message::Unsubscribe(self.prefix.clone()))
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+
+    /// Whether the connection to the broker is currently established
+    pub fn is_connected(&self) -> bool {
+        *self.online.borrow()
+    }
+
+    /// subscribe_under is a convenience method for subscribing to a topic underneath our topic prefix
+    pub async fn subscribe_under<S: Into<String>>(
+        &self,
+        topic: S,
+    ) -> crate::Result<Receiver<Payload>> {
+        self.scoped(topic).subscribe().await
+    }
+
+    pub async fn publish<B: Into<Bytes>>(&self, payload: B) -> crate::Result<()> {
+        self.send(self.publication(payload)).await
+    }
+
+    /// publish_retained publishes a payload which the broker keeps and delivers to any future subscribers
+    pub async fn publish_retained<B: Into<Bytes>>(&self, payload: B) -> crate::Result<()> {
+        let mut publish = self.publication(payload);
+        publish.retain = true;
+        self.send(publish).await
+    }
+
+    /// respond publishes a payload to the response topic of a request, if it has one (MQTT v5 only). Returns whether
+    /// a response was published.
+    pub async fn respond<B: Into<Bytes>>(
+        &self,
+        request: &Properties,
+        payload: B,
+    ) -> crate::Result<bool> {
+        let Some(ref topic) = request.response_topic else {
+            return Ok(false);
+        };
+
+        let mut publish = self.publication(payload);
+        publish.topic = topic.clone();
+        publish.properties.correlation_data = request.correlation_data.clone();
+        self.send(publish).await?;
+        Ok(true)
+    }
+
+    /// with_user_property returns a handle which attaches a user property to everything it publishes
+    pub fn with_user_property<K: Into<String>, V: Into<String>>(&self, key: K, value: V) -> Self {
+        let mut handle = self.clone();
+        handle
+            .properties
+            .user_properties
+            .push((key.into(), value.into()));
+        handle
+    }
+
+    /// with_content_type returns a handle which marks everything it publishes with a content type
+    pub fn with_content_type<S: Into<String>>(&self, content_type: S) -> Self {
+        let mut handle = self.clone();
+        handle.properties.content_type = Some(content_type.into());
+        handle
+    }
+
+    /// with_message_expiry returns a handle whose messages are discarded by the broker if not delivered in time
+    pub fn with_message_expiry(&self, expiry: Duration) -> Self {
+        let mut handle = self.clone();
+        handle.properties.message_expiry = Some(expiry);
+        handle
+    }
+
+    fn publication<B: Into<Bytes>>(&self, payload: B) -> Publish {
+        let mut publish = Publish::new(&self.prefix, payload);
+        publish.properties = self.properties.clone();
+        publish
+    }
+
+    async fn send(&self, publish: Publish) -> crate::Result<()> {
+        self.tx
+            .send(Message::Publish(publish))
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+
+    /// publish_under is a convenience method for publishing to a topic underneath our topic prefix
+    pub async fn publish_under<S: Into<String>, B: Into<Bytes>>(
+        &self,
+        topic: S,
+        payload: B,
+    ) -> crate::Result<()> {
+        self.scoped(topic).publish(payload).await
+    }
+
+    /// publish_retained_under is a convenience method for publishing a retained payload underneath our topic prefix
+    pub async fn publish_retained_under<S: Into<String>, B: Into<Bytes>>(
+        &self,
+        topic: S,
+        payload: B,
+    ) -> crate::Result<()> {
+        self.scoped(topic).publish_retained(payload).await
+    }
+
+    pub async fn shutdown(self) -> crate::Result<()> {
+        self.tx
+            .send(Message::Shutdown)
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+}
+
+pub(crate) trait Scopable {
+    fn scoped<S: Into<String>>(&self, prefix: S) -> Self;
+}
+
+impl Scopable for Handle {
+    fn scoped<S: Into<String>>(&self, prefix: S) -> Self {
+        Self {
+            prefix: format!("{}/{}", self.prefix, prefix.into()),
+            ..self.clone()
+        }
+    }
+}
+
+impl From<Payload> for Bytes {
+    fn from(payload: Payload) -> Self {
+        payload.bytes
+    }
+}
+
+impl std::ops::Deref for Payload {
+    type Target = Bytes;
+
+    fn deref(&self) -> &Self::Target {
+        &self.bytes
+    }
+}
+
+#[test]
+fn test_buffer_drops_oldest() {
+    let mut buffer = Buffer::restore(BufferOptions {
+        capacity: 2,
+        ..Default::default()
+    });
+
+    for n in 1..=3 {
+        buffer.push(Publish::new("topic", n.to_string()));
+    }
+
+    let payloads: Vec<_> = buffer.queue.iter().map(|p| p.payload.clone()).collect();
+    assert_eq!(payloads, ["2", "3"]);
+    assert_eq!(buffer.dropped, 1);
+}
+
+#[test]
+fn test_buffer_save_and_restore() {
+    let file = std::env::temp_dir().join(format!("modbus-mqtt-buffer-{}", std::process::id()));
+    let options = BufferOptions {
+        file: Some(file.clone()),
+        ..Default::default()
+    };
+
+    let mut buffer = Buffer::restore(options.clone());
+    let mut retained = Publish::new("a", "1");
+    retained.retain = true;
+    buffer.push(retained);
+    let mut other = Publish::new("b", "\"two\"");
+    other.qos = QoS::AtMostOnce;
+    other.properties.content_type = Some("application/json".into());
+    buffer.push(other);
+    buffer.save();
+
+    let restored = Buffer::restore(options).spilled();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored[0].topic, "a");
+    assert!(restored[0].retain);
+    assert_eq!(restored[1].payload, "\"two\"");
+    assert_eq!(restored[1].qos, QoS::AtMostOnce);
+    assert_eq!(
+        restored[1].properties.content_type.as_deref(),
+        Some("application/json")
+    );
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn test_saved_payloads() {
+    for payload in [&b"{\"value\": 1}"[..], &[0xff, 0x00, 0xfe], b""] {
+        let saved = Saved::from(Publish::new("topic", payload.to_vec()));
+        let json = serde_json::to_string(&saved).unwrap();
+        let restored = Publish::try_from(serde_json::from_str::<Saved>(&json).unwrap()).unwrap();
+        assert_eq!(restored.payload, payload, "{json}");
+    }
+
+    // Text is saved as is, so the file stays readable
+    let saved = Saved::from(Publish::new("topic", "1.5"));
+    assert_eq!(saved.payload.as_deref(), Some("1.5"));
+    assert_eq!(saved.payload_base64, None);
+}
+
+#[test]
+fn test_buffer_spills_oldest() {
+    let file = std::env::temp_dir().join(format!("modbus-mqtt-spill-{}", std::process::id()));
+    let mut buffer = Buffer::restore(BufferOptions {
+        capacity: 2,
+        file: Some(file.clone()),
+        ..Default::default()
+    });
+
+    for n in 1..=5 {
+        buffer.push(Publish::new("topic", n.to_string()));
+    }
+    assert_eq!(buffer.spilled().len(), 3);
+    assert_eq!(buffer.dropped, 0);
+
+    // Saving puts what's in memory behind what was spilled, so that order is kept across restarts
+    buffer.save();
+    let payloads: Vec<_> = Buffer::restore(buffer.options.clone())
+        .spilled()
+        .into_iter()
+        .map(|p| p.payload)
+        .collect();
+    assert_eq!(payloads, ["1", "2", "3", "4", "5"]);
+
+    // Beyond the file's capacity, the oldest are dropped and counted
+    let mut buffer = Buffer::restore(BufferOptions {
+        file_capacity: 3,
+        ..buffer.options
+    });
+    buffer.push(Publish::new("topic", "6"));
+    buffer.save();
+    let payloads: Vec<_> = buffer.spilled().into_iter().map(|p| p.payload).collect();
+    assert_eq!(payloads, ["4", "5", "6"]);
+    assert_eq!(buffer.dropped, 3);
+    buffer.spill.unwrap().clear().unwrap();
+    assert!(!file.exists());
+}
+
+#[test]
+fn test_buffer_status() {
+    let mut buffer = Buffer::restore(BufferOptions {
+        status_topic: Some("status".into()),
+        ..Default::default()
+    });
+    let now = Instant::now();
+
+    let (status, publish) = buffer.status(now).unwrap();
+    assert_eq!(publish.topic, "status");
+    assert_eq!(
+        publish.payload,
+        r#"{"buffered":0,"capacity":1000,"dropped":0}"#
+    );
+    buffer.published_status = Some((status, now));
+    assert!(buffer.status(now).is_none());
+
+    // Changes are published at most every `STATUS_INTERVAL`...
+    buffer.push(Publish::new("a", "1"));
+    assert!(buffer.status(now).is_none());
+    let (status, _) = buffer.status(now + STATUS_INTERVAL).unwrap();
+    assert_eq!(status.buffered, 1);
+    buffer.published_status = Some((status, now));
+
+    // ...except for the buffer emptying
+    buffer.queue.clear();
+    assert_eq!(buffer.status(now).unwrap().0.buffered, 0);
+}
+
+#[tokio::test]
+async fn test_flush_skips_invalid_publishes() {
+    let options = Options::try_from(url::Url::parse("mqtt://localhost/?client_id=test").unwrap());
+    let mut connection = new(options.unwrap(), BufferOptions::default()).await;
+    connection.connected = true;
+
+    for topic in ["a/+", "a", "a/#/b", "b"] {
+        connection.buffer.push(Publish::new(topic, "1"));
+    }
+    connection.flush();
+
+    // Publishes which are never accepted are dropped rather than holding up those behind them
+    assert!(connection.buffer.queue.is_empty());
+
+    // Once the client's channel (of 32, 2 of which are taken) is full, the rest stay buffered
+    for n in 0..40 {
+        connection.buffer.push(Publish::new("a", n.to_string()));
+    }
+    connection.flush();
+    assert_eq!(connection.buffer.queue.len(), 10);
+    assert_eq!(connection.buffer.queue[0].payload, "30");
+}
+
+#[tokio::test]
+async fn test_flush_sends_spilled_first() {
+    let file = std::env::temp_dir().join(format!("modbus-mqtt-flush-{}", std::process::id()));
+    let options = Options::try_from(url::Url::parse("mqtt://localhost/?client_id=test").unwrap());
+    let buffer = BufferOptions {
+        capacity: 1,
+        file: Some(file.clone()),
+        ..Default::default()
+    };
+    let mut connection = new(options.unwrap(), buffer).await;
+
+    for n in 1..=3 {
+        connection.buffer.push(Publish::new("a", n.to_string()));
+    }
+    assert_eq!(connection.buffer.spilled().len(), 2);
+
+    connection.connected = true;
+    connection.flush();
+    assert!(connection.buffer.spilled().is_empty());
+    assert!(connection.buffer.queue.is_empty());
+    assert!(!file.exists());
+}
+
+#[tokio::test]
+async fn test_unsubscribe() {
+    let options = Options::try_from(url::Url::parse("mqtt://localhost/?client_id=test").unwrap());
+    let mut connection = new(options.unwrap(), BufferOptions::default()).await;
+    let handle = connection.handle("a".into());
+
+    let (first, second) = (channel(1), channel(1));
+    for (tx, _) in [&first, &second] {
+        let subscribe = Message::Subscribe("a/state".into(), tx.clone());
+        connection.handle_request(subscribe).await.unwrap();
+    }
+    assert!(!handle.is_connected());
+
+    // The subscription is kept while anyone is still receiving from it
+    drop(first);
+    let unsubscribe = Message::Unsubscribe("a/state".into());
+    connection
+        .handle_request(unsubscribe.clone())
+        .await
+        .unwrap();
+    assert_eq!(connection.subscriptions["a/state"].len(), 1);
+
+    drop(second);
+    connection.handle_request(unsubscribe).await.unwrap();
+    assert!(connection.subscriptions.is_empty());
+}
@@ -0,0 +1,231 @@
+//! A file of publishes which didn't fit in the in-memory buffer, as JSON lines, oldest first.
+//!
+//! Sent publishes aren't removed from the front of the file one by one, as that would mean rewriting it each time.
+//! Instead, the offset of the oldest unsent line is kept alongside it in `$file.offset`, and the file is only rewritten
+//! once most of it has been sent.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+/// Don't bother rewriting the file to drop sent lines until they take up at least this much of it
+const COMPACT_AFTER: u64 = 64 * 1024;
+
+pub(super) struct Spill {
+    path: PathBuf,
+
+    /// Maximum number of unsent lines to keep, beyond which the oldest are dropped
+    capacity: usize,
+
+    /// Position in the file of the oldest unsent line
+    offset: u64,
+
+    /// Number of unsent lines
+    len: usize,
+}
+
+impl Spill {
+    /// Open the file at `path`, picking up where a previous run left off.
+    pub fn open(path: PathBuf, capacity: usize) -> Spill {
+        let mut spill = Spill {
+            path,
+            capacity,
+            offset: 0,
+            len: 0,
+        };
+
+        let size = fs::metadata(&spill.path).map_or(0, |metadata| metadata.len());
+        spill.offset = fs::read_to_string(spill.offset_path())
+            .ok()
+            .and_then(|offset| offset.trim().parse().ok())
+            .filter(|&offset| offset <= size)
+            .unwrap_or(0);
+        spill.len = spill.count().unwrap_or(0);
+
+        spill
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append lines behind those already in the file, dropping the oldest beyond its capacity. Returns the number of
+    /// lines dropped.
+    pub fn append(&mut self, lines: &[String]) -> io::Result<usize> {
+        let mut data = String::new();
+        for line in lines {
+            data.push_str(line);
+            data.push('\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(data.as_bytes())?;
+        self.len += lines.len();
+
+        let excess = self.len.saturating_sub(self.capacity);
+        if excess > 0 {
+            let dropped = self.read(excess)?;
+            self.consume(&dropped)?;
+        }
+        Ok(excess)
+    }
+
+    /// Read up to `count` of the oldest unsent lines, each including its trailing newline.
+    pub fn read(&self, count: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut lines = vec![];
+        if self.len == 0 {
+            return Ok(lines);
+        }
+
+        let mut reader = self.reader()?;
+        while lines.len() < count.min(self.len) {
+            let mut line = vec![];
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    /// Mark the oldest `lines` (as returned by `read`) as sent, removing the file once every line has been.
+    pub fn consume(&mut self, lines: &[Vec<u8>]) -> io::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        self.len = self.len.saturating_sub(lines.len());
+        self.offset += lines.iter().map(|line| line.len() as u64).sum::<u64>();
+
+        if self.len == 0 {
+            return self.clear();
+        }
+
+        let size = fs::metadata(&self.path)?.len();
+        if self.offset >= COMPACT_AFTER && self.offset * 2 >= size {
+            return self.compact();
+        }
+
+        fs::write(self.offset_path(), self.offset.to_string())
+    }
+
+    /// Drop every line, removing the file.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.len = 0;
+        self.offset = 0;
+        for path in [self.path.clone(), self.offset_path()] {
+            match fs::remove_file(path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite the file without the lines which have already been sent.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+
+        io::copy(&mut self.reader()?, &mut File::create(&temp)?)?;
+        // Should the rename not happen, this only means the sent lines are sent again
+        fs::write(self.offset_path(), "0")?;
+        fs::rename(&temp, &self.path)?;
+        debug!(sent = self.offset, file = ?self.path, "compacted MQTT spill file");
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn count(&self) -> io::Result<usize> {
+        let mut count = 0;
+        let mut reader = self.reader()?;
+        let mut line = vec![];
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            count += 1;
+            line.clear();
+        }
+        if count > self.capacity {
+            warn!(
+                count,
+                capacity = self.capacity,
+                "MQTT spill file is over capacity"
+            );
+        }
+        Ok(count)
+    }
+
+    fn reader(&self) -> io::Result<BufReader<File>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        Ok(BufReader::new(file))
+    }
+
+    fn offset_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".offset");
+        path.into()
+    }
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("modbus-mqtt-{name}-{}", std::process::id()))
+}
+
+#[test]
+fn test_spill() {
+    let path = temp_path("spill");
+    let lines = |lines: Vec<Vec<u8>>| -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect()
+    };
+
+    let mut spill = Spill::open(path.clone(), 3);
+    assert_eq!(spill.append(&["1".into(), "2".into()]).unwrap(), 0);
+    let sent = spill.read(1).unwrap();
+    assert_eq!(lines(sent.clone()), ["1\n"]);
+    spill.consume(&sent).unwrap();
+
+    // Picks up from the offset after a restart
+    let mut spill = Spill::open(path.clone(), 3);
+    assert_eq!(spill.len(), 1);
+    assert_eq!(lines(spill.read(10).unwrap()), ["2\n"]);
+
+    // The oldest are dropped beyond capacity
+    assert_eq!(
+        spill.append(&["3".into(), "4".into(), "5".into()]).unwrap(),
+        1
+    );
+    assert_eq!(spill.len(), 3);
+    assert_eq!(lines(spill.read(10).unwrap()), ["3\n", "4\n", "5\n"]);
+
+    let sent = spill.read(10).unwrap();
+    spill.consume(&sent).unwrap();
+    assert_eq!(spill.len(), 0);
+    assert!(!path.exists());
+    assert!(!spill.offset_path().exists());
+}
+
+#[test]
+fn test_spill_compacts() {
+    let path = temp_path("spill-compact");
+    let line = "x".repeat(1023);
+
+    let mut spill = Spill::open(path.clone(), 1000);
+    spill.append(&vec![line.clone(); 100]).unwrap();
+    let sent = spill.read(70).unwrap();
+    spill.consume(&sent).unwrap();
+
+    // Once most of the file has been sent, it's rewritten without the sent lines
+    assert_eq!(spill.offset, 0);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 30 * 1024);
+    assert_eq!(Spill::open(path.clone(), 1000).len(), 30);
+
+    spill.clear().unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,198 @@
+//! TLS configuration for `mqtts://` and `wss://` connections, for brokers which use a private CA or require clients to
+//! authenticate with a certificate.
+
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rumqttc::Transport;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use super::TlsOptions;
+
+/// Replace the default TLS settings of an encrypted transport with ones built from `tls`.
+pub(super) fn configure(transport: Transport, tls: &TlsOptions) -> crate::Result<Transport> {
+    let config = client_config(tls)?;
+    match transport {
+        Transport::Tls(_) => Ok(Transport::tls_with_config(config.into())),
+        #[cfg(feature = "ws")]
+        Transport::Wss(_) => Ok(Transport::wss_with_config(config.into())),
+        _ => Err("TLS options require an mqtts:// or wss:// MQTT URL".into()),
+    }
+}
+
+fn client_config(tls: &TlsOptions) -> crate::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let certs = match tls.ca_file {
+        Some(ref path) => read_certs(path)?,
+        None => rustls_native_certs::load_native_certs()?,
+    };
+    for cert in certs {
+        roots
+            .add(cert)
+            .map_err(|error| format!("invalid CA certificate: {error}"))?;
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    match (&tls.client_cert, &tls.client_key) {
+        (None, None) => Ok(builder.with_no_client_auth()),
+        (Some(cert), Some(key)) => {
+            let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+                .ok_or_else(|| format!("no private key found in {}", key.display()))?;
+            Ok(builder
+                .with_client_auth_cert(read_certs(cert)?, key)
+                .map_err(|error| format!("invalid client certificate: {error}"))?)
+        }
+        _ => Err("--client-cert and --client-key must be given together".into()),
+    }
+}
+
+fn read_certs(
+    path: &Path,
+) -> crate::Result<Vec<rumqttc::tokio_rustls::rustls::pki_types::CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+fn fixture(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/mqtt/fixtures")
+        .join(name)
+}
+
+#[test]
+fn test_client_config() {
+    let tls = TlsOptions {
+        ca_file: Some(fixture("ca.pem")),
+        client_cert: Some(fixture("client.pem")),
+        client_key: Some(fixture("client.key")),
+    };
+    let config = client_config(&tls).unwrap();
+    assert!(config.client_auth_cert_resolver.has_certs());
+
+    let tls = TlsOptions {
+        ca_file: Some(fixture("ca.pem")),
+        ..Default::default()
+    };
+    let config = client_config(&tls).unwrap();
+    assert!(!config.client_auth_cert_resolver.has_certs());
+}
+
+#[test]
+fn test_client_config_errors() {
+    let config = |ca_file: &str, client_cert: Option<&str>, client_key: Option<&str>| {
+        client_config(&TlsOptions {
+            ca_file: Some(fixture(ca_file)),
+            client_cert: client_cert.map(fixture),
+            client_key: client_key.map(fixture),
+        })
+    };
+
+    // Missing files
+    assert!(config("missing.pem", None, None).is_err());
+    assert!(config("ca.pem", Some("missing.pem"), Some("client.key")).is_err());
+    assert!(config("ca.pem", Some("client.pem"), Some("missing.key")).is_err());
+
+    // Files without the expected contents
+    assert!(config("client.key", None, None).is_err());
+    assert!(config("ca.pem", Some("client.key"), Some("client.key")).is_err());
+    assert!(config("ca.pem", Some("client.pem"), Some("client.pem")).is_err());
+
+    // A certificate without its key
+    assert!(config("ca.pem", Some("client.pem"), None).is_err());
+}
+
+/// Connect to a stand-in broker over TLS, which requires a client certificate issued by the fixture CA and answers the
+/// first CONNECT with a CONNACK. Returns whether the connection was acknowledged.
+#[cfg(test)]
+async fn handshake(tls: TlsOptions) -> bool {
+    use super::client::{Client, Event, Options};
+    use rumqttc::tokio_rustls::{
+        rustls::{server::WebPkiClientVerifier, ServerConfig},
+        TlsAcceptor,
+    };
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{timeout, Duration};
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&fixture("ca.pem")).unwrap() {
+        roots.add(cert).unwrap();
+    }
+    let key = File::open(fixture("server.key")).unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key))
+        .unwrap()
+        .unwrap();
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(WebPkiClientVerifier::builder(roots.into()).build().unwrap())
+        .with_single_cert(read_certs(&fixture("server.pem")).unwrap(), key)
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut stream = TlsAcceptor::from(Arc::new(config)).accept(stream).await?;
+
+        // A CONNECT packet, whose remaining length fits in a byte
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        assert_eq!(header[0], 0x10);
+        stream.read_exact(&mut vec![0; header[1] as usize]).await?;
+
+        stream.write_all(&[0x20, 2, 0, 0]).await?;
+        // Keep the connection open until the client is done with it
+        stream.read(&mut [0]).await
+    });
+
+    let url = format!("mqtts://localhost:{port}/?client_id=test");
+    let mut options = Options::try_from(url::Url::parse(&url).unwrap()).unwrap();
+    options.set_tls(&tls).unwrap();
+    let mut client = Client::new(options, 1);
+
+    let connected = async {
+        loop {
+            match client.poll().await {
+                Ok(Event::Connected) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+    };
+    timeout(Duration::from_secs(5), connected).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_handshake() {
+    assert!(
+        handshake(TlsOptions {
+            ca_file: Some(fixture("ca.pem")),
+            client_cert: Some(fixture("client.pem")),
+            client_key: Some(fixture("client.key")),
+        })
+        .await
+    );
+
+    // The broker requires a client certificate
+    assert!(
+        !handshake(TlsOptions {
+            ca_file: Some(fixture("ca.pem")),
+            ..Default::default()
+        })
+        .await
+    );
+
+    // The broker's certificate isn't trusted without the CA which issued it
+    assert!(
+        !handshake(TlsOptions {
+            ca_file: Some(fixture("client.pem")),
+            client_cert: Some(fixture("client.pem")),
+            client_key: Some(fixture("client.key")),
+        })
+        .await
+    );
+}
@@ -1,13 +1,13 @@
 use crate::{modbus, mqtt};
 
-use rumqttc::MqttOptions;
 use std::future::Future;
 use tokio::sync::{broadcast, mpsc};
 use tracing::{error, info};
 
 pub async fn run<P: Into<String> + Send>(
     prefix: P,
-    mut mqtt_options: MqttOptions,
+    mut mqtt_options: mqtt::Options,
+    mut buffer_options: mqtt::BufferOptions,
     shutdown: impl Future,
 ) -> crate::Result<()> {
     let prefix = prefix.into();
@@ -15,16 +15,15 @@ pub async fn run<P: Into<String> + Send>(
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
-    mqtt_options.set_last_will(rumqttc::LastWill {
-        topic: prefix.clone(),
-        message: "offline".into(),
-        qos: rumqttc::QoS::AtMostOnce,
-        retain: false,
-    });
+    mqtt_options.set_last_will(prefix.clone(), "offline");
+    buffer_options
+        .status_topic
+        .get_or_insert_with(|| format!("{prefix}/mqtt/buffer"));
+
     let client_id = mqtt_options.client_id();
-    let mut mqtt_connection = mqtt::new(mqtt_options).await;
+    let mut mqtt_connection = mqtt::new(mqtt_options, buffer_options).await;
+    mqtt_connection.set_birth(prefix.clone(), "online");
     let mqtt = mqtt_connection.handle(prefix.clone());
-    mqtt.publish("online").await?;
     info!(client_id, "MQTT connection established");
 
     let mut connector = modbus::connector::new(
@@ -32,7 +31,7 @@ pub async fn run<P: Into<String> + Send>(
         (notify_shutdown.subscribe(), shutdown_complete_tx.clone()).into(),
     );
 
-    tokio::spawn(async move {
+    let mqtt_task = tokio::spawn(async move {
         if let Err(err) = mqtt_connection.run().await {
             error!(cause = %err, "MQTT connection error");
         }
@@ -50,7 +49,13 @@ pub async fn run<P: Into<String> + Send>(
 
     // We want MQTT to be the last thing to shutdown, so it gets shutdown after everything else
     shutdown_complete_rx.recv().await;
+    mqtt.publish_retained("offline").await?;
     mqtt.shutdown().await?;
 
+    // Wait for the disconnect, and for anything unsent to be saved, before the process exits
+    if let Err(err) = mqtt_task.await {
+        error!(cause = %err, "MQTT connection task failed");
+    }
+
     Ok(())
 }
@@ -1,4 +1,4 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 
 use tokio_modbus::{
     prelude::{Client, Request, Response},
@@ -79,6 +79,19 @@ impl Client for Context {
                         _ => panic!("this should not happen"),
                     })
             }
+            MaskWriteRegister(address, and_mask, or_mask) => {
+                let current = match self.call(Request::ReadHoldingRegisters(address, 1)).await? {
+                    Response::ReadHoldingRegisters(words) => words.first().copied(),
+                    _ => panic!("this should not happen"),
+                };
+                let current = current.ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "no register value returned")
+                })?;
+                let word = (current & and_mask) | (or_mask & !and_mask);
+                self.call(Request::WriteMultipleRegisters(address, vec![word]))
+                    .await?;
+                Ok(Response::MaskWriteRegister(address, and_mask, or_mask))
+            }
             Disconnect => todo!(),
             _ => unimplemented!("Sungrow doesn't use or expose this"),
         }